@@ -9,6 +9,9 @@ extern crate pango;
 use self::notify_rust::Notification;
 
 use util::*;
+use html;
+use command;
+use command::Action;
 use self::chrono::prelude::*;
 
 use self::secret_service::SecretService;
@@ -17,9 +20,11 @@ use self::secret_service::EncryptionType;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
+use std::cmp;
 use std::collections::HashMap;
 use std::process::Command;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use gio::ApplicationExt;
 use glib;
@@ -37,8 +42,13 @@ use types::MemberList;
 use types::Message;
 use types::Protocol;
 use types::Room;
+use types::NewRoom;
 use types::RoomList;
 use types::Event;
+use types::Receipt;
+use types::PowerLevels;
+use types::PowerLevelsChanges;
+use types::Reaction;
 
 use widgets;
 use cache;
@@ -53,6 +63,8 @@ derror!(secret_service::SsError, Error::SecretServiceError);
 
 
 const APP_ID: &'static str = "org.gnome.Fractal";
+// edits of each room that we keep while the edited message isn't loaded
+const MAX_PENDING_EDITS: usize = 100;
 
 
 struct TmpMsg {
@@ -67,7 +79,66 @@ pub struct AppOp {
     pub backend: Sender<backend::BKCommand>,
 
     pub syncing: bool,
+    pub since: String,
+    pub filter: (String, String),
     tmp_msgs: Vec<TmpMsg>,
+    // messages with a gap before that we're filling
+    filling_gaps: Vec<String>,
+    // the gap of a limited sync without messages, it's set in the next
+    // message of the room
+    pending_gaps: HashMap<String, String>,
+    // room id -> edits of messages that aren't loaded yet
+    pending_edits: HashMap<String, Vec<Message>>,
+    // users typing in each room
+    typing: HashMap<String, Vec<String>>,
+    // room where we're typing and when we told the server
+    typing_sent: Option<(String, Instant)>,
+    // message id -> box with the read receipts of the active room messages
+    receipt_boxes: HashMap<String, gtk::Box>,
+    // message id -> box with the reactions of the active room messages
+    reaction_boxes: HashMap<String, gtk::Box>,
+    // "New messages" divider of the active room, while it's not seen
+    unread_divider: Option<gtk::Widget>,
+    // pages of history that we can still load looking for the fully read
+    // marker, after clicking the jump to unread button
+    unread_pages: u32,
+    // we're waiting for the fully read marker of the active room, so we
+    // can't move it yet
+    fully_read_pending: bool,
+    // quoted message that we're looking for in the history, and the pages
+    // that we can still load, after clicking a quote
+    quote_jump: Option<(String, u32)>,
+    // userid -> (presence, status message)
+    presence: HashMap<String, (String, String)>,
+    // userid -> presence dots of the active room messages
+    presence_dots: HashMap<String, Vec<gtk::Label>>,
+    // our own presence and status message
+    my_presence: (String, String),
+    // we set ourselves unavailable after some time without activity
+    last_activity: Instant,
+    auto_idle: bool,
+    // our message that we're editing in the message entry
+    editing: Option<Message>,
+    // the message that we're replying to
+    replying: Option<Message>,
+    // (room id, event id) -> quoted messages that aren't in the room
+    // messages, None while we're asking for them to the server
+    pub quotes: Arc<Mutex<HashMap<(String, String), Option<Message>>>>,
+    // message id -> widget of the active room messages
+    msg_widgets: HashMap<String, gtk::Widget>,
+    // the messages are sent as markdown
+    markdown: bool,
+    // the m.direct account data, user id -> direct chat rooms
+    direct: HashMap<String, Vec<String>>,
+    // the users invited to the active room, that haven't joined yet
+    invited: MemberList,
+    // the users banned from the active room
+    banned: MemberList,
+    // the users selected in the invite dialog, shared with the rows
+    invite_selected: Arc<Mutex<Vec<String>>>,
+    // the power level of each permission in the room settings, the key is
+    // the PowerLevels field, "event:type" or "user:uid"
+    perm_spins: Arc<Mutex<Vec<(String, gtk::SpinButton)>>>,
 
     pub username: String,
     pub uid: String,
@@ -89,6 +160,7 @@ pub enum RoomPanel {
     Room,
     NoRoom,
     Loading,
+    Invite,
 }
 
 impl AppOp {
@@ -104,7 +176,35 @@ impl AppOp {
             username: String::new(),
             uid: String::new(),
             syncing: false,
+            since: String::new(),
+            filter: (String::new(), String::new()),
             tmp_msgs: vec![],
+            filling_gaps: vec![],
+            pending_gaps: HashMap::new(),
+            pending_edits: HashMap::new(),
+            typing: HashMap::new(),
+            typing_sent: None,
+            receipt_boxes: HashMap::new(),
+            reaction_boxes: HashMap::new(),
+            unread_divider: None,
+            unread_pages: 0,
+            fully_read_pending: false,
+            quote_jump: None,
+            presence: HashMap::new(),
+            presence_dots: HashMap::new(),
+            my_presence: (strn!("online"), String::new()),
+            last_activity: Instant::now(),
+            auto_idle: false,
+            editing: None,
+            replying: None,
+            quotes: Arc::new(Mutex::new(HashMap::new())),
+            msg_widgets: HashMap::new(),
+            markdown: true,
+            direct: HashMap::new(),
+            invited: HashMap::new(),
+            banned: HashMap::new(),
+            invite_selected: Arc::new(Mutex::new(vec![])),
+            perm_spins: Arc::new(Mutex::new(vec![])),
         }
     }
 
@@ -228,6 +328,11 @@ impl AppOp {
     }
 
     pub fn set_uid(&mut self, uid: &str) {
+        // the cached sync token and filter are only valid for the same account
+        if self.uid != uid {
+            self.since = String::new();
+            self.filter = (String::new(), String::new());
+        }
         self.uid = String::from(uid);
     }
 
@@ -376,6 +481,9 @@ impl AppOp {
             self.set_rooms(r, None);
             self.username = data.username;
             self.uid = data.uid;
+            self.since = data.since;
+            self.filter = (data.filter_id, data.filter);
+            self.direct = data.direct;
         } else {
             self.room_panel(RoomPanel::Loading);
         }
@@ -398,11 +506,42 @@ impl AppOp {
             RoomPanel::Loading => "loading",
             RoomPanel::Room => "room_view",
             RoomPanel::NoRoom => "noroom",
+            RoomPanel::Invite => "invite",
         };
 
         s.set_visible_child_name(v);
     }
 
+    /// Continues syncing from the cached sync token, if any, so we only get
+    /// the changes since the last time that the app was running.
+    pub fn resume_sync(&mut self) {
+        if !self.filter.0.is_empty() {
+            let (id, def) = self.filter.clone();
+            self.backend.send(BKCommand::SetFilter(id, def)).unwrap();
+        }
+        if !self.direct.is_empty() {
+            self.backend.send(BKCommand::SetDirect(self.direct.clone())).unwrap();
+        } else if !self.since.is_empty() {
+            // the caches of older versions don't have the direct chats, and
+            // the sync only gives them to us when they change
+            self.backend.send(BKCommand::GetDirect).unwrap();
+        }
+        if !self.since.is_empty() {
+            self.backend.send(BKCommand::SetSince(self.since.clone())).unwrap();
+        }
+        self.sync();
+    }
+
+    pub fn set_sync_filter(&mut self, id: String, def: String) {
+        self.filter = (id, def);
+        self.cache_rooms();
+    }
+
+    pub fn synced(&mut self, since: String) {
+        self.syncing = false;
+        self.since = since;
+    }
+
     pub fn sync(&mut self) {
         if !self.syncing {
             self.syncing = true;
@@ -432,10 +571,18 @@ impl AppOp {
                 0 => String::new(),
                 i => format!("{}", i),
             };
+            let hl = v.highlight > 0;
 
-            store.insert_with_values(None, None, &[0, 1, 2], &[&v.name, &v.id, &ns]);
+            let section = self.get_room_section(&store, &v);
+            store.insert_with_values(section.as_ref(), None, &[0, 1, 2, 3],
+                                     &[&v.name, &v.id, &ns, &hl]);
         }
 
+        self.gtk_builder
+            .get_object::<gtk::TreeView>("rooms_tree_view")
+            .expect("Couldn't find rooms_tree_view in ui file.")
+            .expand_all();
+
         let mut godef = def;
         if !self.active_room.is_empty() {
             if let Some(r) = self.rooms.get(&self.active_room) {
@@ -453,9 +600,252 @@ impl AppOp {
         self.cache_rooms();
     }
 
+    pub fn new_rooms(&mut self, rooms: Vec<Room>) {
+        for mut r in rooms {
+            // we only update the rooms that we know if it was an invitation
+            // and now we've joined, maybe from other client
+            let known = match self.rooms.get(&r.id) {
+                Some(old) => !old.inv || r.inv,
+                None => false,
+            };
+
+            if known {
+                continue;
+            }
+
+            // the m.direct could be updated before we get the joined room,
+            // when we accept a direct chat invite
+            if !r.inv && is_direct(&self.direct, &r.id) {
+                r.direct = true;
+            }
+
+            self.remove_room(&r.id);
+            self.add_room(r);
+        }
+
+        self.cache_rooms();
+    }
+
+    pub fn add_room(&mut self, room: Room) {
+        let store: gtk::TreeStore = self.gtk_builder
+            .get_object("rooms_tree_store")
+            .expect("Couldn't find rooms_tree_store in ui file.");
+
+        let section = self.get_room_section(&store, &room);
+
+        // looking for the position to keep the list sorted by name, the
+        // sections are always at the top
+        let name = room.name.to_lowercase();
+        let mut pos = 0;
+        if let Some(iter) = store.iter_children(section.as_ref()) {
+            loop {
+                let v0 = store.get_value(&iter, 0);
+                let n: &str = v0.get().unwrap();
+                let v1 = store.get_value(&iter, 1);
+                let id: &str = v1.get().unwrap();
+                if !id.is_empty() && n.to_lowercase() > name {
+                    break;
+                }
+                pos += 1;
+                if !store.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        let ns = match room.notifications {
+            0 => String::new(),
+            i => format!("{}", i),
+        };
+        let hl = room.highlight > 0;
+
+        store.insert_with_values(section.as_ref(), Some(pos), &[0, 1, 2, 3],
+                                 &[&room.name, &room.id, &ns, &hl]);
+        self.rooms.insert(room.id.clone(), room);
+
+        self.gtk_builder
+            .get_object::<gtk::TreeView>("rooms_tree_view")
+            .expect("Couldn't find rooms_tree_view in ui file.")
+            .expand_all();
+    }
+
+    /// Returns the row of the rooms list section where this room should be,
+    /// creating it if needed. Rooms without section are in the top level so
+    /// None is returned in that case.
+    ///
+    /// Section rows are rows with an empty room id.
+    fn get_room_section(&self, store: &gtk::TreeStore, room: &Room) -> Option<gtk::TreeIter> {
+        let name = match room {
+            r if r.inv => "Invites",
+            r if r.direct => "Direct messages",
+            _ => { return None; }
+        };
+
+        if let Some(iter) = store.get_iter_first() {
+            loop {
+                let v0 = store.get_value(&iter, 0);
+                let n: &str = v0.get().unwrap();
+                let v1 = store.get_value(&iter, 1);
+                let id: &str = v1.get().unwrap();
+                if id.is_empty() && n == name {
+                    return Some(iter);
+                }
+                if !store.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        Some(store.insert_with_values(None, Some(0), &[0, 1, 2, 3], &[&name, &"", &"", &false]))
+    }
+
+    /// Looks for the room row in the rooms list, in the top level or inside
+    /// a section.
+    fn find_room_iter(&self, store: &gtk::TreeStore, roomid: &str) -> Option<gtk::TreeIter> {
+        let mut sections = vec![];
+
+        if let Some(iter) = store.get_iter_first() {
+            loop {
+                let v1 = store.get_value(&iter, 1);
+                let id: &str = v1.get().unwrap();
+                if id == roomid {
+                    return Some(iter);
+                }
+                if id.is_empty() {
+                    sections.push(iter.clone());
+                }
+                if !store.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        for section in sections {
+            if let Some(iter) = store.iter_children(Some(&section)) {
+                loop {
+                    let v1 = store.get_value(&iter, 1);
+                    let id: &str = v1.get().unwrap();
+                    if id == roomid {
+                        return Some(iter);
+                    }
+                    if !store.iter_next(&iter) {
+                        break;
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    pub fn remove_room(&mut self, roomid: &str) {
+        let store: gtk::TreeStore = self.gtk_builder
+            .get_object("rooms_tree_store")
+            .expect("Couldn't find rooms_tree_store in ui file.");
+
+        self.rooms.remove(roomid);
+        self.pending_edits.remove(roomid);
+        self.remove_room_row(&store, roomid);
+
+        if self.active_room == roomid {
+            self.active_room = String::new();
+            self.room_panel(RoomPanel::NoRoom);
+        }
+    }
+
+    fn remove_room_row(&self, store: &gtk::TreeStore, roomid: &str) {
+        if let Some(iter) = self.find_room_iter(store, roomid) {
+            let parent = store.iter_parent(&iter);
+            store.remove(&iter);
+
+            // removing empty sections
+            if let Some(p) = parent {
+                if !store.iter_has_child(&p) {
+                    store.remove(&p);
+                }
+            }
+        }
+    }
+
+    /// Updates the direct chats, the rooms that change are moved to or
+    /// from the direct messages section of the rooms list
+    pub fn set_direct_rooms(&mut self, direct: HashMap<String, Vec<String>>) {
+        let store: gtk::TreeStore = self.gtk_builder
+            .get_object("rooms_tree_store")
+            .expect("Couldn't find rooms_tree_store in ui file.");
+
+        let changed: Vec<Room> = self.rooms.values()
+            .filter(|r| !r.inv && r.direct != is_direct(&direct, &r.id))
+            .cloned()
+            .collect();
+
+        for mut r in changed {
+            r.direct = !r.direct;
+            self.remove_room_row(&store, &r.id);
+            self.add_room(r);
+        }
+
+        self.direct = direct;
+        self.cache_rooms();
+    }
+
+    /// Goes to the direct chat with the user, creating it if we haven't one
+    pub fn direct_chat(&mut self, uid: String) {
+        let empty = vec![];
+        let existing = self.direct.get(&uid).unwrap_or(&empty).iter()
+            .filter_map(|id| self.rooms.get(id))
+            .find(|r| !r.inv)
+            .cloned();
+
+        match existing {
+            Some(r) => { self.set_active_room(&r); }
+            None => { self.backend.send(BKCommand::DirectChat(uid)).unwrap(); }
+        }
+    }
+
+    pub fn left_room(&mut self, roomid: String, ev: Option<Event>) {
+        let (name, inv) = match self.rooms.get(&roomid) {
+            Some(r) => (r.name.clone(), r.inv),
+            None => { return; }
+        };
+
+        self.remove_room(&roomid);
+        self.cache_rooms();
+
+        // telling the user why if someone else has removed us from the room
+        let ev = match ev {
+            Some(ref e) if e.sender != self.uid => e,
+            _ => { return; }
+        };
+
+        let sender = match self.members.get(&ev.sender) {
+            Some(m) => m.get_alias(),
+            None => ev.sender.clone(),
+        };
+
+        let mut msg = match ev.content["membership"].as_str() {
+            Some("ban") => format!("You've been banned from {} by {}", name, sender),
+            _ if inv => format!("The invitation to {} has been withdrawn by {}", name, sender),
+            _ => format!("You've been kicked from {} by {}", name, sender),
+        };
+
+        if let Some(reason) = ev.content["reason"].as_str() {
+            if !reason.is_empty() {
+                msg = format!("{}\nReason: {}", msg, reason);
+            }
+        }
+
+        self.show_error(&msg);
+    }
+
     pub fn cache_rooms(&self) {
         // serializing rooms
-        if let Err(_) = cache::store(&self.rooms, self.username.clone(), self.uid.clone()) {
+        if let Err(_) = cache::store(&self.rooms,
+                                    self.since.clone(),
+                                    self.filter.clone(),
+                                    &self.direct,
+                                    self.username.clone(),
+                                    self.uid.clone()) {
             println!("Error caching rooms");
         };
     }
@@ -477,6 +867,12 @@ impl AppOp {
         for ch in messages.get_children().iter().skip(1) {
             messages.remove(ch);
         }
+        self.receipt_boxes.clear();
+        self.reaction_boxes.clear();
+        self.presence_dots.clear();
+        self.msg_widgets.clear();
+        self.unread_divider = None;
+        self.update_jump_unread();
     }
 
     pub fn set_active_room_by_id(&mut self, roomid: String) {
@@ -491,14 +887,28 @@ impl AppOp {
     }
 
     pub fn set_active_room(&mut self, room: &Room) {
+        // we aren't typing in the old room anymore
+        self.stop_typing();
         self.active_room = room.id.clone();
 
+        if room.inv {
+            self.show_inv_panel(room);
+            return;
+        }
+
         self.remove_messages();
+        self.unread_pages = 0;
+        self.quote_jump = None;
+        // we can only reply to messages of the same room
+        self.cancel_reply();
 
         let mut getmessages = true;
         for msg in room.messages.iter() {
             self.add_room_message(msg, MsgPos::Bottom);
         }
+        for msg in room.messages.iter().filter(|m| m.gap.is_some()) {
+            self.fill_gap(msg);
+        }
         if !room.messages.is_empty() {
             getmessages = false;
             if let Some(msg) = room.messages.iter().last() {
@@ -507,10 +917,17 @@ impl AppOp {
             }
         }
 
+        self.fully_read_pending = room.fully_read.is_empty();
+        if self.fully_read_pending {
+            self.backend.send(BKCommand::GetFullyRead(room.id.clone())).unwrap();
+        }
+
         // getting room details
         self.backend.send(BKCommand::SetRoom(room.clone())).unwrap();
 
         self.members.clear();
+        self.invited.clear();
+        self.banned.clear();
         let members = self.gtk_builder
             .get_object::<gtk::ListStore>("members_store")
             .expect("Can't find members_store in ui file.");
@@ -531,6 +948,7 @@ impl AppOp {
         edit.set_text(&room.name);
 
         self.set_current_room_avatar(room.avatar.clone());
+        self.update_typing_label();
 
         if getmessages {
             self.backend.send(BKCommand::GetRoomMessages(self.active_room.clone())).unwrap();
@@ -540,6 +958,66 @@ impl AppOp {
         }
     }
 
+    pub fn show_inv_panel(&self, room: &Room) {
+        let name = self.gtk_builder
+            .get_object::<gtk::Label>("invite_name")
+            .expect("Can't find invite_name in ui file.");
+        let text = self.gtk_builder
+            .get_object::<gtk::Label>("invite_text")
+            .expect("Can't find invite_text in ui file.");
+        let image = self.gtk_builder
+            .get_object::<gtk::Image>("invite_avatar")
+            .expect("Can't find invite_avatar in ui file.");
+
+        name.set_markup(&format!("<big><b>{}</b></big>", markup(&room.name)));
+
+        let msg = match room.inv_sender {
+            Some(ref sender) => format!("{} ({}) has invited you to join this room",
+                                        sender.get_alias(), sender.uid),
+            None => strn!("You've been invited to join this room"),
+        };
+        text.set_text(&msg);
+
+        image.set_from_icon_name("image-missing", 6);
+        let id = room.id.clone();
+        let rname = room.name.clone();
+        let (tx, rx): (Sender<String>, Receiver<String>) = channel();
+        self.backend.send(BKCommand::GetThumbAsync(room.avatar.clone(), tx)).unwrap();
+        gtk::timeout_add(50, move || match rx.try_recv() {
+            Err(_) => gtk::Continue(true),
+            Ok(fname) => {
+                let mut f = fname.clone();
+                if f.is_empty() {
+                    f = draw_identicon(&id, rname.clone(), AvatarMode::Circle).unwrap_or(f);
+                }
+                if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&f, 64, 64, false) {
+                    image.set_from_pixbuf(&pixbuf);
+                }
+                gtk::Continue(false)
+            }
+        });
+
+        self.room_panel(RoomPanel::Invite);
+    }
+
+    pub fn accept_inv(&mut self) {
+        let r = self.active_room.clone();
+        // the direct chat invites are added to the m.direct when we join
+        let direct = match self.rooms.get(&r) {
+            Some(room) if room.direct => room.inv_sender.as_ref().map(|m| m.uid.clone()),
+            _ => None,
+        };
+        self.backend.send(BKCommand::AcceptInv(r, direct)).unwrap();
+        self.room_panel(RoomPanel::Loading);
+    }
+
+    pub fn reject_inv(&mut self) {
+        let r = self.active_room.clone();
+        self.backend.send(BKCommand::RejectInv(r.clone())).unwrap();
+        self.remove_room(&r);
+        self.cache_rooms();
+    }
+
     pub fn set_room_detail(&mut self, roomid: String, key: String, value: String) {
         if let Some(r) = self.rooms.get_mut(&roomid) {
             let k: &str = &key;
@@ -647,138 +1125,1232 @@ impl AppOp {
 
         if msg.room == self.active_room {
             let m;
+            let receipts;
+            let reactions;
+            let mut presence = None;
             {
                 let mb = widgets::MessageBox::new(msg, &self);
                 m = match prev {
-                    Some(ref p) if p.sender == msg.sender => mb.small_widget(),
-                    _ => mb.widget(),
-                }
+                    Some(ref p) if p.sender == msg.sender && msg.gap.is_none() => mb.small_widget(),
+                    _ => {
+                        presence = Some(mb.presence_label());
+                        mb.widget()
+                    }
+                };
+                receipts = mb.receipts_box();
+                reactions = mb.reactions_box();
+            }
+            if !msg.id.is_empty() {
+                self.receipt_boxes.insert(msg.id.clone(), receipts);
+                self.reaction_boxes.insert(msg.id.clone(), reactions);
+                self.msg_widgets.insert(msg.id.clone(), m.clone().upcast::<gtk::Widget>());
             }
+            if let Some(p) = presence {
+                self.presence_dots.entry(msg.sender.clone()).or_insert(vec![]).push(p);
+            }
+
+            let gap = match (&msg.gap, &prev) {
+                (&Some(ref token), &Some(ref p)) => Some(self.build_gap_widget(msg, token, p)),
+                _ => None,
+            };
 
             match msgpos {
-                MsgPos::Bottom => messages.add(&m),
-                MsgPos::Top => messages.insert(&m, 1),
+                MsgPos::Bottom => {
+                    if let Some(ref g) = gap {
+                        messages.add(g);
+                    }
+                    messages.add(&m);
+                    if self.is_first_unread_before(msg) {
+                        let divider = self.build_unread_divider();
+                        messages.add(&divider);
+                        self.unread_divider = Some(divider.upcast::<gtk::Widget>());
+                    }
+                }
+                MsgPos::Top => {
+                    // the marker can be in the older messages
+                    if self.is_first_unread_before(msg) {
+                        let divider = self.build_unread_divider();
+                        messages.insert(&divider, 1);
+                        self.unread_divider = Some(divider.upcast::<gtk::Widget>());
+                    }
+                    messages.insert(&m, 1);
+                    if let Some(ref g) = gap {
+                        messages.insert(g, 1);
+                    }
+                }
             };
             self.remove_tmp_room_message(msg);
-        } else {
-            self.update_room_notifications(&msg.room, |n| n + 1);
         }
     }
 
-    pub fn add_tmp_room_message(&mut self, msg: &Message) {
-        let m;
-        let messages = self.gtk_builder
-            .get_object::<gtk::ListBox>("message_list")
-            .expect("Can't find message_list in ui file.");
+    /// True if this message is the fully read marker of the room and there
+    /// are newer messages after it
+    fn is_first_unread_before(&self, msg: &Message) -> bool {
+        if self.unread_divider.is_some() || msg.id.is_empty() {
+            return false;
+        }
 
-        {
-            let mb = widgets::MessageBox::new(msg, &self);
-            m = mb.widget();
+        match self.rooms.get(&msg.room) {
+            Some(r) if r.fully_read == msg.id => {
+                match r.messages.iter().position(|m| m.id == msg.id) {
+                    Some(pos) => pos + 1 < r.messages.len(),
+                    None => false,
+                }
+            }
+            _ => false,
         }
+    }
 
-        messages.add(&m);
-        if let Some(w) = messages.get_children().iter().last() {
-            self.tmp_msgs.push(TmpMsg {
-                    msg: msg.clone(),
-                    widget: w.clone(),
-            });
+    fn build_unread_divider(&self) -> gtk::Box {
+        let divider = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let label = gtk::Label::new("");
+        label.set_markup("<span color=\"#ff888e\"><b>New messages</b></span>");
 
-            self.scroll_down();
-        };
+        let left = gtk::Separator::new(gtk::Orientation::Horizontal);
+        let right = gtk::Separator::new(gtk::Orientation::Horizontal);
+        left.set_valign(gtk::Align::Center);
+        right.set_valign(gtk::Align::Center);
+
+        divider.pack_start(&left, true, true, 0);
+        divider.pack_start(&label, false, false, 0);
+        divider.pack_start(&right, true, true, 0);
+        divider.show_all();
+
+        divider
     }
 
-    pub fn remove_tmp_room_message(&mut self, msg: &Message) {
+    /// True if the "New messages" divider is in the visible part of the
+    /// message list
+    fn unread_divider_visible(&self) -> bool {
+        let divider = match self.unread_divider {
+            Some(ref d) => d,
+            None => { return false; }
+        };
+
         let messages = self.gtk_builder
             .get_object::<gtk::ListBox>("message_list")
             .expect("Can't find message_list in ui file.");
+        let scroll = self.gtk_builder
+            .get_object::<gtk::ScrolledWindow>("messages_scroll")
+            .expect("Can't find message_scroll in ui file.");
 
-        let mut rmidxs = vec![];
-
-        for (i, t) in self.tmp_msgs.iter().enumerate() {
-            if t.msg.sender == msg.sender &&
-               t.msg.mtype == msg.mtype &&
-               t.msg.room == msg.room &&
-               t.msg.body == msg.body {
+        let adj = match scroll.get_vadjustment() {
+            Some(a) => a,
+            None => { return false; }
+        };
 
-                messages.remove(&t.widget);
-                //t.widget.destroy();
-                rmidxs.push(i);
+        match divider.translate_coordinates(&messages, 0, 0) {
+            Some((_, y)) => {
+                let y = y as f64;
+                y >= adj.get_value() && y <= adj.get_value() + adj.get_page_size()
             }
+            None => false,
         }
+    }
 
-        for i in rmidxs {
-            self.tmp_msgs.remove(i);
+    fn message_list_at_bottom(&self) -> bool {
+        let scroll = self.gtk_builder
+            .get_object::<gtk::ScrolledWindow>("messages_scroll")
+            .expect("Can't find message_scroll in ui file.");
+
+        match scroll.get_vadjustment() {
+            Some(adj) => adj.get_value() + adj.get_page_size() >= adj.get_upper() - 1.0,
+            None => false,
         }
     }
 
-    pub fn update_room_notifications(&self, roomid: &str, f: fn(i32) -> i32) {
-        let store: gtk::TreeStore = self.gtk_builder
-            .get_object("rooms_tree_store")
-            .expect("Couldn't find rooms_tree_store in ui file.");
-
-        if let Some(iter) = store.get_iter_first() {
-            loop {
-                let v1 = store.get_value(&iter, 1);
-                let id: &str = v1.get().unwrap();
-                let v2 = store.get_value(&iter, 2);
-                let ns: &str = v2.get().unwrap();
-                let res: Result<i32, _> = ns.parse();
-                let n: i32 = f(res.unwrap_or(0));
-                let formatted = match n {
-                    0 => String::from(""),
-                    i => format!("{}", i),
-                };
-                if id == roomid {
-                    store.set_value(&iter, 2, &gtk::Value::from(&formatted));
-                }
-                if !store.iter_next(&iter) {
-                    break;
-                }
+    /// True if we know the fully read marker of the active room, but it's
+    /// older than the loaded messages, so there's no divider yet
+    fn fully_read_not_loaded(&self) -> bool {
+        match self.rooms.get(&self.active_room) {
+            Some(r) => {
+                !r.fully_read.is_empty() && !r.messages.is_empty() &&
+                !r.messages.iter().any(|m| m.id == r.fully_read)
             }
+            None => false,
         }
     }
 
-    pub fn mark_as_read(&self, msg: &Message) {
-        self.backend.send(BKCommand::MarkAsRead(msg.room.clone(),
-                                                msg.id.clone())).unwrap();
-    }
+    /// Called when the message list is scrolled, the fully read marker is
+    /// moved to the last message only when the user has seen the first
+    /// unread message, or when there's no divider and we're at the bottom.
+    /// If the marker isn't loaded the user hasn't seen the messages after
+    /// it, so it isn't moved. Neither is moved until we know the server one,
+    /// or we would replace it with the last message.
+    pub fn check_fully_read(&mut self) {
+        if self.fully_read_pending {
+            return;
+        }
 
-    pub fn add_room_member(&mut self, m: Member) {
-        let store: gtk::ListStore = self.gtk_builder
-            .get_object("members_store")
-            .expect("Couldn't find members_store in ui file.");
+        let seen = match self.unread_divider {
+            Some(_) => self.unread_divider_visible(),
+            None if self.fully_read_not_loaded() => false,
+            None => self.message_list_at_bottom(),
+        };
 
-        let name = m.get_alias();
+        if seen {
+            self.unread_divider = None;
 
-        // only show 200 members...
-        if self.members.len() < 200 {
-            store.insert_with_values(None, &[0, 1], &[&name, &(m.uid)]);
+            let last = match self.rooms.get(&self.active_room) {
+                Some(r) => r.messages.iter().rev().find(|m| !m.id.is_empty()).cloned(),
+                None => None,
+            };
+
+            if let Some(msg) = last {
+                let moved = match self.rooms.get_mut(&self.active_room) {
+                    Some(r) if r.fully_read != msg.id => {
+                        r.fully_read = msg.id.clone();
+                        true
+                    }
+                    _ => false,
+                };
+
+                if moved {
+                    self.backend.send(BKCommand::SetFullyRead(msg.room.clone(),
+                                                              msg.id.clone())).unwrap();
+                }
+            }
         }
 
-        self.members.insert(m.uid.clone(), m);
+        self.update_jump_unread();
     }
 
-    pub fn member_clicked(&self, uid: String) {
-        println!("member clicked: {}, {:?}", uid, self.members.get(&uid));
-    }
+    fn update_jump_unread(&self) {
+        let btn = self.gtk_builder
+            .get_object::<gtk::Button>("jump_unread_button")
+            .expect("Can't find jump_unread_button in ui file.");
 
-    pub fn send_message(&mut self, msg: String) {
+        if self.unread_divider.is_some() || self.fully_read_not_loaded() {
+            btn.show();
+        } else {
+            btn.hide();
+        }
+    }
+
+    /// Goes to the "New messages" divider, loading the older messages
+    /// until we find the fully read marker
+    pub fn jump_to_unread(&mut self) {
+        if let Some(ref d) = self.unread_divider {
+            self.scroll_to_widget(d);
+            return;
+        }
+
+        if self.fully_read_not_loaded() {
+            self.quote_jump = None;
+            self.unread_pages = 10;
+            self.load_more_messages();
+        }
+    }
+
+    /// Called after loading older messages, to continue looking for the
+    /// fully read marker. If we reach the room start, the marker isn't a
+    /// message that we can show, so the divider goes after the oldest one.
+    fn continue_jump_to_unread(&mut self, loaded: bool) {
+        if self.unread_pages == 0 {
+            return;
+        }
+
+        if self.unread_divider.is_some() {
+            self.unread_pages = 0;
+            self.jump_to_unread();
+            return;
+        }
+
+        if !loaded {
+            self.unread_pages = 0;
+            let first = match self.rooms.get(&self.active_room) {
+                Some(r) => r.messages.iter().find(|m| !m.id.is_empty()).map(|m| m.id.clone()),
+                None => None,
+            };
+            if let Some(id) = first {
+                if let Some(r) = self.rooms.get_mut(&self.active_room) {
+                    r.fully_read = id;
+                }
+                self.redraw_room_messages();
+                self.jump_to_unread();
+            }
+            return;
+        }
+
+        self.unread_pages -= 1;
+        if self.unread_pages > 0 {
+            self.load_more_messages();
+        }
+    }
+
+    /// Scrolls the message list to show this widget at the top
+    fn scroll_to_widget(&self, w: &gtk::Widget) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+        let scroll = self.gtk_builder
+            .get_object::<gtk::ScrolledWindow>("messages_scroll")
+            .expect("Can't find message_scroll in ui file.");
+
+        let y = match w.translate_coordinates(&messages, 0, 0) {
+            Some((_, y)) => y as f64,
+            None => { return; }
+        };
+
+        // the scroll is done outside of this call, because it calls
+        // to check_fully_read
+        gtk::timeout_add(50, move || {
+            if let Some(adj) = scroll.get_vadjustment() {
+                adj.set_value(y);
+            }
+            gtk::Continue(false)
+        });
+    }
+
+    /// Goes to the original message of a reply, loading the older
+    /// messages until we find it
+    pub fn quote_clicked(&mut self, roomid: String, evid: String) {
+        if roomid != self.active_room {
+            return;
+        }
+
+        if let Some(w) = self.msg_widgets.get(&evid) {
+            self.quote_jump = None;
+            self.scroll_to_widget(w);
+            return;
+        }
+
+        // only one jump at a time, or we would load each page twice
+        self.unread_pages = 0;
+        self.quote_jump = Some((evid, 10));
+        self.load_more_messages();
+    }
+
+    /// Called after loading older messages, to continue looking for the
+    /// quoted message. We stop at the room start.
+    fn continue_quote_jump(&mut self, loaded: bool) {
+        let (evid, pages) = match self.quote_jump.take() {
+            Some(j) => j,
+            None => { return; }
+        };
+
+        if let Some(w) = self.msg_widgets.get(&evid) {
+            self.scroll_to_widget(w);
+            return;
+        }
+
+        if loaded && pages > 1 {
+            self.quote_jump = Some((evid, pages - 1));
+            self.load_more_messages();
+        }
+    }
+
+    pub fn set_room_fully_read(&mut self, roomid: String, evid: String) {
+        if roomid == self.active_room {
+            self.fully_read_pending = false;
+        }
+
+        let first = match self.rooms.get_mut(&roomid) {
+            Some(r) => {
+                let first = r.fully_read.is_empty();
+                r.fully_read = evid;
+                first
+            }
+            None => { return; }
+        };
+
+        // we didn't know the marker when the room was opened
+        if first && roomid == self.active_room && self.unread_divider.is_none() {
+            self.redraw_room_messages();
+        }
+    }
+
+    /// The room hasn't a fully read marker yet, or we can't get it, so we
+    /// set our own one when the user reads the messages
+    pub fn fully_read_error(&mut self, roomid: String) {
+        if roomid == self.active_room {
+            self.fully_read_pending = false;
+            self.check_fully_read();
+        }
+    }
+
+    fn build_gap_widget(&self, msg: &Message, token: &str, prev: &Message) -> gtk::Button {
+        let btn = gtk::Button::new_with_label("Load missing messages");
+        btn.set_relief(gtk::ReliefStyle::None);
+        btn.show();
+
+        let backend = self.backend.clone();
+        let room = msg.room.clone();
+        let id = msg.id.clone();
+        let from = String::from(token);
+        let until = prev.id.clone();
+        btn.connect_clicked(move |b| {
+            b.set_label("loading...");
+            b.set_sensitive(false);
+            let command = BKCommand::FillGap(room.clone(), id.clone(), from.clone(), until.clone());
+            backend.send(command).unwrap();
+        });
+
+        btn
+    }
+
+    /// Requests the messages that we're missing before this one
+    pub fn fill_gap(&mut self, msg: &Message) {
+        let token = match msg.gap {
+            Some(ref t) => t.clone(),
+            None => { return; }
+        };
+
+        if self.filling_gaps.contains(&msg.id) {
+            return;
+        }
+
+        let until = match self.rooms.get(&msg.room) {
+            Some(r) => {
+                let pos = r.messages.iter().position(|m| m.id == msg.id).unwrap_or(0);
+                match pos {
+                    0 => None,
+                    p => r.messages.get(p - 1).map(|m| m.id.clone()),
+                }
+            }
+            None => None,
+        };
+
+        if let Some(u) = until {
+            self.filling_gaps.push(msg.id.clone());
+            let command = BKCommand::FillGap(msg.room.clone(), msg.id.clone(), token, u);
+            self.backend.send(command).unwrap();
+        }
+    }
+
+    /// The missing messages are before the next message that we get. The
+    /// newest token is kept, going back from it we also get the messages
+    /// of the previous gaps.
+    pub fn room_timeline_gap(&mut self, roomid: String, token: String) {
+        self.pending_gaps.insert(roomid, token);
+    }
+
+    /// The gap is shown again, so the user can retry
+    pub fn room_gap_fill_error(&mut self, roomid: String, msgid: String) {
+        self.filling_gaps.retain(|id| *id != msgid);
+
+        if roomid == self.active_room {
+            self.redraw_room_messages();
+        }
+    }
+
+    pub fn room_gap_filled(&mut self, roomid: String, msgid: String, msgs: Vec<Message>, token: Option<String>) {
+        self.filling_gaps.retain(|id| *id != msgid);
+
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            let pos = match r.messages.iter().position(|m| m.id == msgid) {
+                Some(p) => p,
+                None => { return; }
+            };
+
+            let (edits, msgs): (Vec<Message>, Vec<Message>) = msgs.into_iter()
+                .partition(|m| m.replaces.is_some());
+
+            let mut n = 0;
+            for msg in msgs {
+                if r.messages.iter().any(|m| m.id == msg.id) {
+                    continue;
+                }
+                r.messages.insert(pos + n, msg);
+                n += 1;
+            }
+
+            for e in edits {
+                if !apply_edit(&mut r.messages, &e) {
+                    keep_pending_edit(&mut self.pending_edits, e);
+                }
+            }
+
+            // if we've not reached the last known message, there's still a gap
+            r.messages[pos + n].gap = token;
+        }
+        self.apply_pending_edits(&roomid);
+
+        if roomid == self.active_room {
+            self.redraw_room_messages();
+        }
+    }
+
+    /// Applies the edit to the edited message, or keeps it until we load
+    /// that message. Returns false if the message isn't loaded.
+    fn add_edit(&mut self, edit: Message) -> bool {
+        if let Some(r) = self.rooms.get_mut(&edit.room) {
+            if apply_edit(&mut r.messages, &edit) {
+                return true;
+            }
+        }
+
+        keep_pending_edit(&mut self.pending_edits, edit);
+        false
+    }
+
+    /// Applies the edits that we've got before the edited messages
+    fn apply_pending_edits(&mut self, roomid: &str) {
+        let r = match self.rooms.get_mut(roomid) {
+            Some(r) => r,
+            None => { return; }
+        };
+
+        if let Some(edits) = self.pending_edits.get_mut(roomid) {
+            edits.retain(|e| !apply_edit(&mut r.messages, e));
+        }
+    }
+
+    pub fn redraw_room_messages(&mut self) {
+        let msgs = match self.rooms.get(&self.active_room) {
+            Some(r) => r.messages.clone(),
+            None => { return; }
+        };
+
+        self.remove_messages();
+        for msg in msgs.iter() {
+            self.add_room_message(msg, MsgPos::Bottom);
+        }
+    }
+
+    pub fn add_tmp_room_message(&mut self, msg: &Message) {
+        let m;
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+
+        {
+            let mb = widgets::MessageBox::new(msg, &self);
+            m = mb.widget();
+        }
+
+        messages.add(&m);
+        if let Some(w) = messages.get_children().iter().last() {
+            self.tmp_msgs.push(TmpMsg {
+                    msg: msg.clone(),
+                    widget: w.clone(),
+            });
+
+            self.scroll_down();
+        };
+    }
+
+    pub fn remove_tmp_room_message(&mut self, msg: &Message) {
+        let messages = self.gtk_builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+
+        let mut rmidxs = vec![];
+
+        for (i, t) in self.tmp_msgs.iter().enumerate() {
+            if t.msg.sender == msg.sender &&
+               t.msg.mtype == msg.mtype &&
+               t.msg.room == msg.room &&
+               t.msg.body == msg.body {
+
+                messages.remove(&t.widget);
+                //t.widget.destroy();
+                rmidxs.push(i);
+            }
+        }
+
+        for i in rmidxs {
+            self.tmp_msgs.remove(i);
+        }
+    }
+
+    /// Sets the unread notifications and highlights count of the room, the
+    /// highlights are the notifications that mention us
+    pub fn set_room_notifications(&mut self, roomid: &str, n: i32, h: i32) {
+        let store: gtk::TreeStore = self.gtk_builder
+            .get_object("rooms_tree_store")
+            .expect("Couldn't find rooms_tree_store in ui file.");
+
+        if let Some(r) = self.rooms.get_mut(roomid) {
+            r.notifications = n;
+            r.highlight = h;
+        }
+
+        if let Some(iter) = self.find_room_iter(&store, roomid) {
+            let formatted = match n {
+                0 => String::from(""),
+                i => format!("{}", i),
+            };
+            store.set_value(&iter, 2, &gtk::Value::from(&formatted));
+            store.set_value(&iter, 3, &gtk::Value::from(&(h > 0)));
+        }
+    }
+
+    pub fn room_notifications(&mut self, roomid: String, n: i32, h: i32) {
+        // we're reading the active room, so it'll be marked as read
+        if roomid == self.active_room {
+            return;
+        }
+
+        self.set_room_notifications(&roomid, n, h);
+    }
+
+    pub fn set_room_typing(&mut self, roomid: String, users: Vec<String>) {
+        let users = users.into_iter().filter(|u| *u != self.uid).collect();
+        self.typing.insert(roomid.clone(), users);
+
+        if roomid == self.active_room {
+            self.update_typing_label();
+        }
+    }
+
+    pub fn update_typing_label(&self) {
+        let label = self.gtk_builder
+            .get_object::<gtk::Label>("typing_label")
+            .expect("Can't find typing_label in ui file.");
+
+        let empty = vec![];
+        let users = self.typing.get(&self.active_room).unwrap_or(&empty);
+        let names: Vec<String> = users.iter().map(|u| match self.members.get(u) {
+            Some(m) => m.get_alias(),
+            None => u.clone(),
+        }).collect();
+
+        let text = match names.len() {
+            0 => String::from(""),
+            1 => format!("{} is typing…", names[0]),
+            2 => format!("{} and {} are typing…", names[0], names[1]),
+            _ => String::from("Several people are typing…"),
+        };
+
+        label.set_text(&text);
+    }
+
+    /// Called when the message entry changes, tells the server that we're
+    /// typing in the active room. The notification is resent before the
+    /// server timeout while we keep typing, and it's stopped when the entry
+    /// is empty.
+    pub fn typing_changed(&mut self, typing: bool) {
+        let room = self.active_room.clone();
+
+        if !typing {
+            self.stop_typing();
+            return;
+        }
+
+        let resend = match self.typing_sent {
+            Some((ref r, t)) => *r != room || t.elapsed() > Duration::from_secs(4),
+            None => true,
+        };
+
+        if resend && !room.is_empty() {
+            // we're still typing if it's the same room, so we only stop
+            // the old one
+            if self.typing_sent.as_ref().map(|&(ref r, _)| *r != room).unwrap_or(false) {
+                self.stop_typing();
+            }
+            self.backend.send(BKCommand::SendTyping(room.clone(), true)).unwrap();
+            self.typing_sent = Some((room, Instant::now()));
+        }
+    }
+
+    pub fn stop_typing(&mut self) {
+        if let Some((room, _)) = self.typing_sent.take() {
+            self.backend.send(BKCommand::SendTyping(room, false)).unwrap();
+        }
+    }
+
+    pub fn room_receipts(&mut self, roomid: String, receipts: Vec<Receipt>) {
+        // messages that gain or lose a receipt
+        let mut changed = vec![];
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            for receipt in receipts {
+                if let Some(old) = r.receipts.get(&receipt.uid) {
+                    changed.push(old.event.clone());
+                }
+                changed.push(receipt.event.clone());
+                r.receipts.insert(receipt.uid.clone(), receipt);
+            }
+        }
+
+        if roomid != self.active_room {
+            return;
+        }
+
+        if let Some(r) = self.rooms.get(&roomid) {
+            for msg in r.messages.iter().filter(|m| changed.contains(&m.id)) {
+                if let Some(bx) = self.receipt_boxes.get(&msg.id) {
+                    let mb = widgets::MessageBox::new(msg, &self);
+                    mb.fill_receipts(bx);
+                }
+            }
+        }
+    }
+
+    pub fn mark_as_read(&self, msg: &Message) {
+        self.backend.send(BKCommand::MarkAsRead(msg.room.clone(),
+                                                msg.id.clone())).unwrap();
+    }
+
+    pub fn add_room_member(&mut self, m: Member) {
+        let store: gtk::ListStore = self.gtk_builder
+            .get_object("members_store")
+            .expect("Couldn't find members_store in ui file.");
+
+        let name = m.get_alias();
+
+        let (presence, status) = match self.presence.get(&m.uid) {
+            Some(&(ref p, ref s)) => (presence_markup(p), markup(s)),
+            None => (String::new(), String::new()),
+        };
+
+        // only show 200 members...
+        if self.members.len() < 200 {
+            // the joined members are before the invited group
+            let pos = self.find_member_row(&store, "").map(|(_, p)| p);
+            store.insert_with_values(pos, &[0, 1, 2, 3, 4, 5],
+                                     &[&name, &(m.uid), &presence, &status, &400, &true]);
+        }
+
+        self.members.insert(m.uid.clone(), m);
+    }
+
+    /// The invited and banned users are shown at the end of the members
+    /// list, in groups that start with a row with an empty user id
+    pub fn add_invited_member(&mut self, m: Member) {
+        if self.invited.contains_key(&m.uid) {
+            return;
+        }
+
+        self.add_group_member(&m, "Invited");
+        self.invited.insert(m.uid.clone(), m);
+    }
+
+    pub fn add_banned_member(&mut self, m: Member) {
+        if self.banned.contains_key(&m.uid) {
+            return;
+        }
+
+        self.add_group_member(&m, "Banned");
+        self.banned.insert(m.uid.clone(), m);
+    }
+
+    fn add_group_member(&self, m: &Member, group: &str) {
+        let store: gtk::ListStore = self.gtk_builder
+            .get_object("members_store")
+            .expect("Couldn't find members_store in ui file.");
+
+        if self.find_group_row(&store, group).is_none() {
+            // the invited group is before the banned group
+            let pos = match group {
+                "Invited" => self.find_group_row(&store, "Banned").map(|(_, p)| p),
+                _ => None,
+            };
+            store.insert_with_values(pos, &[0, 1, 2, 3, 4, 5],
+                                     &[&group, &"", &"", &"", &700, &false]);
+        }
+
+        let pos = self.find_group_row(&store, group).map(|(_, p)| p + 1);
+        store.insert_with_values(pos, &[0, 1, 2, 3, 4, 5],
+                                 &[&m.get_alias(), &(m.uid), &"", &"", &400, &false]);
+    }
+
+    /// Looks for the row of the member in the members list and its position
+    fn find_member_row(&self, store: &gtk::ListStore, uid: &str) -> Option<(gtk::TreeIter, u32)> {
+        let mut pos = 0;
+        if let Some(iter) = store.get_iter_first() {
+            loop {
+                let v1 = store.get_value(&iter, 1);
+                let id: &str = v1.get().unwrap();
+                if id == uid {
+                    return Some((iter, pos));
+                }
+                pos += 1;
+                if !store.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        None
+    }
+
+    fn find_group_row(&self, store: &gtk::ListStore, group: &str) -> Option<(gtk::TreeIter, u32)> {
+        let mut pos = 0;
+        if let Some(iter) = store.get_iter_first() {
+            loop {
+                let v0 = store.get_value(&iter, 0);
+                let name: &str = v0.get().unwrap();
+                let v1 = store.get_value(&iter, 1);
+                let id: &str = v1.get().unwrap();
+                if id.is_empty() && name == group {
+                    return Some((iter, pos));
+                }
+                pos += 1;
+                if !store.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Removes the joined, invited or banned member from the members list
+    fn remove_member(&mut self, uid: &str) {
+        let store: gtk::ListStore = self.gtk_builder
+            .get_object("members_store")
+            .expect("Couldn't find members_store in ui file.");
+
+        self.members.remove(uid);
+        self.invited.remove(uid);
+        self.banned.remove(uid);
+
+        if let Some((iter, _)) = self.find_member_row(&store, uid) {
+            store.remove(&iter);
+        }
+
+        // removing the empty groups
+        let groups = [("Invited", self.invited.is_empty()), ("Banned", self.banned.is_empty())];
+        for &(group, empty) in groups.iter() {
+            if !empty {
+                continue;
+            }
+            if let Some((iter, _)) = self.find_group_row(&store, group) {
+                store.remove(&iter);
+            }
+        }
+    }
+
+    /// We can kick or ban other members if our power level in the room is
+    /// enough and greater than their power level. Returns (kick, ban).
+    pub fn can_moderate(&self, uid: &str) -> (bool, bool) {
+        match self.rooms.get(&self.active_room) {
+            Some(r) => {
+                let pl = &r.power_levels;
+                let mine = pl.user_level(&self.uid);
+                let above = mine > pl.user_level(uid);
+                (above && mine >= pl.kick, above && mine >= pl.ban)
+            }
+            None => (false, false),
+        }
+    }
+
+    /// We can remove our own messages and the messages of other members if
+    /// our power level in the room is enough
+    pub fn can_redact(&self, msg: &Message) -> bool {
+        if msg.sender == self.uid {
+            return true;
+        }
+
+        match self.rooms.get(&msg.room) {
+            Some(r) => r.power_levels.user_level(&self.uid) >= r.power_levels.redact,
+            None => false,
+        }
+    }
+
+    pub fn room_power_levels(&mut self, roomid: String, pl: PowerLevels) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.power_levels = pl;
+        }
+    }
+
+    pub fn room_reaction(&mut self, roomid: String, reaction: Reaction) {
+        let target = reaction.target.clone();
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            let rs = r.reactions.entry(target.clone()).or_insert(vec![]);
+            // we get our reactions from the sync too
+            if rs.iter().any(|x| x.id == reaction.id) {
+                return;
+            }
+            rs.push(reaction);
+        }
+
+        if roomid == self.active_room {
+            self.update_reactions(&target);
+        }
+    }
+
+    fn update_reactions(&self, msgid: &str) {
+        let r = match self.rooms.get(&self.active_room) {
+            Some(r) => r,
+            None => { return; }
+        };
+
+        if let Some(msg) = r.messages.iter().find(|m| m.id == msgid) {
+            if let Some(bx) = self.reaction_boxes.get(msgid) {
+                let mb = widgets::MessageBox::new(msg, &self);
+                mb.fill_reactions(bx);
+            }
+        }
+    }
+
+    /// Replaces the content of a removed message with a placeholder, or
+    /// removes the reaction if it's a reaction
+    pub fn room_redaction(&mut self, roomid: String, evid: String) {
+        let mut reaction = None;
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            for rs in r.reactions.values_mut() {
+                if let Some(pos) = rs.iter().position(|x| x.id == evid) {
+                    reaction = Some(rs.remove(pos));
+                    break;
+                }
+            }
+        }
+
+        if let Some(reaction) = reaction {
+            if roomid == self.active_room {
+                self.update_reactions(&reaction.target);
+            }
+            return;
+        }
+
+        let mut found = false;
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            if let Some(msg) = r.messages.iter_mut().find(|m| m.id == evid) {
+                msg.redacted = true;
+                msg.body = String::new();
+                msg.url = String::new();
+                msg.thumb = String::new();
+                found = true;
+            }
+        }
+
+        if found && roomid == self.active_room {
+            self.redraw_room_messages();
+        }
+    }
+
+    pub fn get_presence(&self, uid: &str) -> String {
+        match self.presence.get(uid) {
+            Some(&(ref p, _)) => p.clone(),
+            None => String::new(),
+        }
+    }
+
+    pub fn set_user_presence(&mut self, uid: String, presence: String, status: String) {
+        let dot = presence_markup(&presence);
+
+        if let Some(labels) = self.presence_dots.get(&uid) {
+            for l in labels {
+                l.set_markup(&dot);
+            }
+        }
+
+        let store = self.gtk_builder
+            .get_object::<gtk::ListStore>("members_store")
+            .expect("Can't find members_store in ui file.");
+        if let Some(iter) = store.get_iter_first() {
+            loop {
+                let v1 = store.get_value(&iter, 1);
+                let id: &str = v1.get().unwrap();
+                if id == uid {
+                    store.set_value(&iter, 2, &gtk::Value::from(&dot));
+                    store.set_value(&iter, 3, &gtk::Value::from(&markup(&status)));
+                    break;
+                }
+                if !store.iter_next(&iter) {
+                    break;
+                }
+            }
+        }
+
+        self.presence.insert(uid, (presence, status));
+    }
+
+    /// Sets our presence and status message from the user menu
+    pub fn set_own_presence(&mut self) {
+        let combo = self.gtk_builder
+            .get_object::<gtk::ComboBoxText>("presence_combo")
+            .expect("Can't find presence_combo in ui file.");
+        let entry = self.gtk_builder
+            .get_object::<gtk::Entry>("status_msg_entry")
+            .expect("Can't find status_msg_entry in ui file.");
+
+        let presence = combo.get_active_id().unwrap_or(strn!("online"));
+        let status = entry.get_text().unwrap_or(String::new());
+
+        self.my_presence = (presence, status);
+        self.auto_idle = false;
+        self.send_presence();
+        self.hide_popup();
+    }
+
+    fn send_presence(&self) {
+        let (ref presence, ref status) = self.my_presence;
+        let p = if self.auto_idle {
+            strn!("unavailable")
+        } else {
+            presence.clone()
+        };
+        self.backend.send(BKCommand::SetPresence(p, status.clone())).unwrap();
+    }
+
+    /// Called on user input, if we were set unavailable because of
+    /// inactivity we're back
+    pub fn user_activity(&mut self) {
+        self.last_activity = Instant::now();
+
+        if self.auto_idle {
+            self.auto_idle = false;
+            self.send_presence();
+        }
+    }
+
+    /// Sets us unavailable after five minutes without activity, only if
+    /// we're online, an user chosen presence is never changed
+    pub fn check_idle(&mut self) {
+        if self.auto_idle || self.my_presence.0 != "online" || self.uid.is_empty() {
+            return;
+        }
+
+        if self.last_activity.elapsed() > Duration::from_secs(5 * 60) {
+            self.auto_idle = true;
+            self.send_presence();
+        }
+    }
+
+    /// Runs the command written in the message entry, or sends the text
+    /// as a message. Returns false if the command is wrong, so the text is
+    /// kept in the entry to fix it.
+    pub fn run_composer(&mut self, text: String) -> bool {
+        let action = match command::parse(&text) {
+            Ok(a) => a,
+            Err(usage) => {
+                self.show_command_error(&usage);
+                return false;
+            }
+        };
+
+        let room = self.active_room.clone();
+        let md = self.markdown;
+
+        match action {
+            Action::Send(msg) => { self.send_message(msg, "m.text", md); }
+            Action::Me(msg) => { self.send_message(msg, "m.emote", md); }
+            Action::Plain(msg) => { self.send_message(msg, "m.text", false); }
+            Action::Join(r) => {
+                self.backend.send(BKCommand::JoinRoom(r)).unwrap();
+            }
+            Action::Leave => { self.leave_active_room(); }
+            Action::Nick(name) => {
+                self.backend.send(BKCommand::SetUserName(name)).unwrap();
+            }
+            Action::Topic(topic) => {
+                self.backend.send(BKCommand::SetRoomTopic(room, topic)).unwrap();
+            }
+            Action::Invite(uid) => {
+                self.backend.send(BKCommand::Invite(room, uid)).unwrap();
+            }
+            Action::Kick(uid, reason) => {
+                self.backend.send(BKCommand::Kick(room, uid, reason)).unwrap();
+            }
+            Action::Ban(uid, reason) => {
+                self.backend.send(BKCommand::Ban(room, uid, reason)).unwrap();
+            }
+            Action::Query(uid) => { self.direct_chat(uid); }
+            Action::Markdown(md) => {
+                self.markdown = md;
+            }
+        }
+
+        true
+    }
+
+    /// Shows the usage of the command under the message entry
+    pub fn show_command_error(&self, msg: &str) {
+        let label = self.gtk_builder
+            .get_object::<gtk::Label>("command_label")
+            .expect("Can't find command_label in ui file.");
+        label.set_text(msg);
+        label.show();
+    }
+
+    pub fn hide_command_error(&self) {
+        self.gtk_builder
+            .get_object::<gtk::Label>("command_label")
+            .expect("Can't find command_label in ui file.")
+            .hide();
+    }
+
+    pub fn send_message(&mut self, msg: String, mtype: &str, markdown: bool) {
         let room = self.active_room.clone();
         let now = Local::now();
 
-        let m = Message {
+        let formatted = if markdown {
+            markdown_to_html(&msg)
+        } else {
+            None
+        };
+
+        let mut m = Message {
             sender: self.uid.clone(),
-            mtype: strn!("m.text"),
+            mtype: strn!(mtype),
             body: msg.clone(),
             room: room.clone(),
             date: now,
             thumb: String::from(""),
             url: String::from(""),
             id: String::from(""),
+            gap: None,
+            redacted: false,
+            replaces: None,
+            edited: false,
+            formatted: formatted.clone(),
+            in_reply_to: None,
         };
 
+        self.stop_typing();
+
+        if let Some(orig) = self.editing.take() {
+            self.set_editing_icon(false);
+            if orig.room == room {
+                self.send_edit(&orig, m);
+                return;
+            }
+        }
+
+        // the reply is shown with the quote, but it's sent with the quoted
+        // message as fallback
+        let mut sent = m.clone();
+        if let Some(parent) = self.replying.clone() {
+            self.cancel_reply();
+            if parent.room == room {
+                m.in_reply_to = Some(parent.id.clone());
+                let html = formatted.unwrap_or(html_escape(&msg));
+                let (body, formatted) = reply_fallback(&parent, &msg, &html);
+                sent = m.clone();
+                sent.body = body;
+                sent.formatted = Some(formatted);
+            }
+        }
+
         self.add_tmp_room_message(&m);
-        self.backend.send(BKCommand::SendMsg(m)).unwrap();
+        self.backend.send(BKCommand::SendMsg(sent)).unwrap();
+    }
+
+    /// Shows the message entry text rendered as markdown over the entry,
+    /// if the preview button is active
+    pub fn update_markdown_preview(&self) {
+        let button = self.gtk_builder
+            .get_object::<gtk::ToggleButton>("markdown_preview_button")
+            .expect("Can't find markdown_preview_button in ui file.");
+        let preview = self.gtk_builder
+            .get_object::<gtk::Label>("markdown_preview")
+            .expect("Can't find markdown_preview in ui file.");
+        let entry: gtk::Entry = self.gtk_builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+
+        let text = entry.get_text().unwrap_or_default();
+        if !button.get_active() || text.is_empty() {
+            preview.hide();
+            return;
+        }
+
+        let (text, md) = match command::parse(&text) {
+            Ok(Action::Send(t)) | Ok(Action::Me(t)) => (t, self.markdown),
+            Ok(Action::Plain(t)) => (t, false),
+            _ => {
+                preview.hide();
+                return;
+            }
+        };
+        let formatted = if md {
+            markdown_to_html(&text)
+        } else {
+            None
+        };
+        let pango = match formatted {
+            Some(ref f) => html::to_pango(f).unwrap_or(markup(&text)),
+            None => markup(&text),
+        };
+
+        preview.set_markup(&pango);
+        preview.show();
+    }
+
+    /// Shows the message that we're replying to over the message entry
+    pub fn reply_clicked(&mut self, msg: Message) {
+        let bx = self.gtk_builder
+            .get_object::<gtk::Box>("reply_box")
+            .expect("Can't find reply_box in ui file.");
+        let label = self.gtk_builder
+            .get_object::<gtk::Label>("reply_label")
+            .expect("Can't find reply_label in ui file.");
+        let entry: gtk::Entry = self.gtk_builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+
+        let name = match self.members.get(&msg.sender) {
+            Some(m) => m.get_alias(),
+            None => msg.sender.clone(),
+        };
+        let body = msg.body.lines().next().unwrap_or("").to_string();
+        label.set_markup(&format!("Replying to <b>{}</b>: <span alpha=\"60%\">{}</span>",
+                                  html_escape(&name), html_escape(&body)));
+
+        self.cancel_edit();
+        self.replying = Some(msg);
+        bx.show();
+        entry.grab_focus();
+    }
+
+    pub fn cancel_reply(&mut self) {
+        self.gtk_builder
+            .get_object::<gtk::Box>("reply_box")
+            .expect("Can't find reply_box in ui file.")
+            .hide();
+        self.replying = None;
+    }
+
+    fn send_edit(&mut self, orig: &Message, msg: Message) {
+        let mut edit = msg;
+        edit.replaces = Some(orig.id.clone());
+
+        // the edit is shown before the server echo
+        let mut edited = false;
+        if let Some(r) = self.rooms.get_mut(&orig.room) {
+            edited = apply_edit(&mut r.messages, &edit);
+        }
+        if edited {
+            self.redraw_room_messages();
+        }
+
+        self.backend.send(BKCommand::SendMsg(edit)).unwrap();
+    }
+
+    /// Puts the message in the message entry to edit it, the next message
+    /// that we send will replace it
+    pub fn edit_clicked(&mut self, msg: Message) {
+        let entry: gtk::Entry = self.gtk_builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+
+        let body = msg.body.clone();
+        self.cancel_reply();
+        self.editing = Some(msg);
+        self.set_editing_icon(true);
+
+        // the text is changed outside of this call, because the entry
+        // changed signal calls to typing_changed
+        gtk::timeout_add(50, move || {
+            entry.set_text(&body);
+            entry.grab_focus();
+            entry.set_position(-1);
+            gtk::Continue(false)
+        });
+    }
+
+    /// Returns true if we were editing a message
+    pub fn cancel_edit(&mut self) -> bool {
+        self.set_editing_icon(false);
+        self.editing.take().is_some()
+    }
+
+    fn set_editing_icon(&self, editing: bool) {
+        let entry: gtk::Entry = self.gtk_builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+
+        if editing {
+            entry.set_icon_from_icon_name(gtk::EntryIconPosition::Primary,
+                                          Some("document-edit-symbolic"));
+            entry.set_icon_tooltip_text(gtk::EntryIconPosition::Primary,
+                                        Some("Editing message, press Esc to cancel"));
+        } else {
+            entry.set_icon_from_icon_name(gtk::EntryIconPosition::Primary, None);
+        }
     }
 
     pub fn attach_file(&mut self) {
@@ -969,11 +2541,41 @@ impl AppOp {
         });
     }
 
-    pub fn show_room_messages(&mut self, msgs: Vec<Message>, init: bool) {
-        for msg in msgs.iter() {
+    pub fn show_room_messages(&mut self, newmsgs: Vec<Message>, init: bool) {
+        let mut msgs = vec![];
+        let mut edited = false;
+
+        for m in newmsgs.iter() {
+            if m.replaces.is_some() {
+                // the edit isn't shown, so the gap goes to the next message
+                if let Some(ref gap) = m.gap {
+                    self.room_timeline_gap(m.room.clone(), gap.clone());
+                }
+
+                let found = self.add_edit(m.clone());
+                edited = edited || (found && m.room == self.active_room);
+                continue;
+            }
+
+            let mut msg = m.clone();
+            if msg.gap.is_none() {
+                msg.gap = self.pending_gaps.remove(&msg.room);
+            }
             if let Some(r) = self.rooms.get_mut(&msg.room) {
+                // there's no gap if we don't have previous messages
+                if r.messages.is_empty() {
+                    msg.gap = None;
+                }
+
+                // ignoring the messages that we already have, we can get
+                // them again when resuming the sync from the cache
+                let known = r.messages.iter().any(|m| m.id == msg.id);
+                if !init && !msg.id.is_empty() && known {
+                    continue;
+                }
                 r.messages.push(msg.clone());
             }
+            msgs.push(msg);
         }
 
         for msg in msgs.iter() {
@@ -998,23 +2600,56 @@ impl AppOp {
             }
         }
 
+        let gaps: Vec<Message> = msgs.iter()
+            .filter(|x| x.room == self.active_room && x.gap.is_some())
+            .cloned().collect();
+        for msg in gaps {
+            self.fill_gap(&msg);
+        }
+
+        if edited {
+            self.redraw_room_messages();
+        }
+
         if init {
             self.room_panel(RoomPanel::Room);
         }
     }
 
     pub fn show_room_messages_top(&mut self, msgs: Vec<Message>) {
+        let (edits, msgs): (Vec<Message>, Vec<Message>) = msgs.into_iter()
+            .partition(|m| m.replaces.is_some());
+
         for msg in msgs.iter().rev() {
             if let Some(r) = self.rooms.get_mut(&msg.room) {
                 r.messages.insert(0, msg.clone());
             }
         }
 
+        // the edits in this page are older than the pending ones
+        for e in edits {
+            self.add_edit(e);
+        }
+        let roomid = msgs.first().map(|m| m.room.clone());
+        let msgs: Vec<Message> = match roomid {
+            Some(id) => {
+                self.apply_pending_edits(&id);
+                match self.rooms.get(&id) {
+                    Some(r) => r.messages.iter().take(msgs.len()).cloned().collect(),
+                    None => msgs,
+                }
+            }
+            None => msgs,
+        };
+
         for msg in msgs.iter().rev() {
             self.add_room_message(msg, MsgPos::Top);
         }
 
         self.load_more_normal();
+        self.continue_jump_to_unread(!msgs.is_empty());
+        self.continue_quote_jump(!msgs.is_empty());
+        self.update_jump_unread();
     }
 
     pub fn show_room_dialog(&self) {
@@ -1022,34 +2657,352 @@ impl AppOp {
             .get_object::<gtk::Dialog>("room_config_dialog")
             .expect("Can't find room_config_dialog in ui file.");
 
+        self.fill_permissions();
         dialog.show();
     }
 
-    pub fn leave_active_room(&mut self) {
-        let r = self.active_room.clone();
-        self.backend.send(BKCommand::LeaveRoom(r.clone())).unwrap();
-        self.rooms.remove(&r);
-        self.active_room = String::new();
-        self.room_panel(RoomPanel::NoRoom);
+    /// Fills the permissions page of the room settings with the power
+    /// levels of the active room. The levels can be changed only if our
+    /// level allows to change the m.room.power_levels, and never over our
+    /// own level.
+    pub fn fill_permissions(&self) {
+        let bx = self.gtk_builder
+            .get_object::<gtk::Box>("room_permissions_box")
+            .expect("Can't find room_permissions_box in ui file.");
+
+        for ch in bx.get_children().iter() {
+            bx.remove(ch);
+        }
+        let mut spins = self.perm_spins.lock().unwrap();
+        spins.clear();
 
-        let store: gtk::TreeStore = self.gtk_builder
-            .get_object("rooms_tree_store")
-            .expect("Couldn't find rooms_tree_store in ui file.");
+        let pl = match self.rooms.get(&self.active_room) {
+            Some(r) => r.power_levels.clone(),
+            None => { return; }
+        };
+        let mine = pl.user_level(&self.uid);
+        let editable = mine >= pl.event_level("m.room.power_levels", true);
+
+        // members with their own level, the admins first
+        bx.pack_start(&build_permission_title("Members"), false, false, 0);
+        let users_box = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        let mut users: Vec<(&String, &i32)> = pl.users.iter().collect();
+        users.sort_by(|x, y| y.1.cmp(x.1).then(x.0.cmp(y.0)));
+        for (uid, level) in users {
+            let name = match self.members.get(uid) {
+                Some(m) => format!("{} ({})", m.get_alias(), uid),
+                None => uid.clone(),
+            };
+            let sensitive = editable && (*level < mine || *uid == self.uid);
+            let (row, spin) = build_permission_row(&name, *level, mine, sensitive);
+            users_box.pack_start(&row, false, false, 0);
+            spins.push((format!("user:{}", uid), spin));
+        }
+        bx.pack_start(&users_box, false, false, 0);
+
+        if editable {
+            let add = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+            let entry = gtk::Entry::new();
+            entry.set_placeholder_text("@user:server");
+            let spin = gtk::SpinButton::new_with_range(0.0, mine as f64, 1.0);
+            spin.set_value(50.0);
+            let btn = gtk::Button::new_with_label("Add");
+            add.pack_start(&entry, true, true, 0);
+            add.pack_start(&spin, false, false, 0);
+            add.pack_start(&btn, false, false, 0);
+            bx.pack_start(&add, false, false, 0);
+
+            let perm_spins = self.perm_spins.clone();
+            btn.connect_clicked(move |_| {
+                let uid = strn!(entry.get_text().unwrap_or_default().trim());
+                let key = format!("user:{}", uid);
+                let mut spins = perm_spins.lock().unwrap();
+                if !is_uid(&uid) || spins.iter().any(|s| s.0 == key) {
+                    return;
+                }
 
-        if let Some(iter) = store.get_iter_first() {
-            loop {
-                let v1 = store.get_value(&iter, 1);
-                let id: &str = v1.get().unwrap();
-                if id == r {
-                    store.remove(&iter);
+                let level = spin.get_value_as_int();
+                let (row, s) = build_permission_row(&uid, level, mine, true);
+                users_box.pack_start(&row, false, false, 0);
+                users_box.show_all();
+                spins.push((key, s));
+                entry.set_text("");
+            });
+        }
+
+        // the level needed to do each thing
+        bx.pack_start(&build_permission_title("Permissions"), false, false, 0);
+        let perms = [
+            ("event:m.room.message", "Send messages", pl.event_level("m.room.message", false)),
+            ("invite", "Invite users", pl.invite),
+            ("kick", "Kick users", pl.kick),
+            ("ban", "Ban users", pl.ban),
+            ("redact", "Remove messages of others", pl.redact),
+            ("event:m.room.name", "Change the room name", pl.event_level("m.room.name", true)),
+            ("event:m.room.topic", "Change the topic", pl.event_level("m.room.topic", true)),
+            ("event:m.room.avatar", "Change the avatar", pl.event_level("m.room.avatar", true)),
+            ("event:m.room.canonical_alias", "Change the main address",
+             pl.event_level("m.room.canonical_alias", true)),
+            ("event:m.room.power_levels", "Change the permissions",
+             pl.event_level("m.room.power_levels", true)),
+            ("users_default", "Default level of the members", pl.users_default),
+            ("events_default", "Default level to send events", pl.events_default),
+            ("state_default", "Default level to change the settings", pl.state_default),
+        ];
+        for &(key, label, level) in perms.iter() {
+            let (row, spin) = build_permission_row(label, level, mine, editable && level <= mine);
+            bx.pack_start(&row, false, false, 0);
+            spins.push((strn!(key), spin));
+        }
+
+        bx.show_all();
+    }
+
+    /// The power levels with the changes of the permissions page, None if
+    /// nothing has changed
+    fn permissions_changes(&self) -> Option<PowerLevelsChanges> {
+        let old = match self.rooms.get(&self.active_room) {
+            Some(r) => r.power_levels.clone(),
+            None => { return None; }
+        };
+
+        let mut changes = PowerLevelsChanges::new();
+        for &(ref key, ref spin) in self.perm_spins.lock().unwrap().iter() {
+            let v = spin.get_value_as_int();
+            let prev = match key.as_str() {
+                "invite" => old.invite,
+                "kick" => old.kick,
+                "ban" => old.ban,
+                "redact" => old.redact,
+                "users_default" => old.users_default,
+                "events_default" => old.events_default,
+                "state_default" => old.state_default,
+                k if k.starts_with("event:") => {
+                    let ev = &k["event:".len()..];
+                    if v != old.event_level(ev, ev != "m.room.message") {
+                        changes.events.insert(strn!(ev), v);
+                    }
+                    continue;
                 }
-                if !store.iter_next(&iter) {
-                    break;
+                k if k.starts_with("user:") => {
+                    let uid = &k["user:".len()..];
+                    if v != old.user_level(uid) {
+                        changes.users.insert(strn!(uid), v);
+                    }
+                    continue;
                 }
+                _ => { continue; }
+            };
+
+            if prev != v {
+                changes.levels.insert(key.clone(), v);
+            }
+        }
+
+        if changes.is_empty() {
+            None
+        } else {
+            Some(changes)
+        }
+    }
+
+    pub fn show_new_room_dialog(&self) {
+        let dialog = self.gtk_builder
+            .get_object::<gtk::Dialog>("new_room_dialog")
+            .expect("Can't find new_room_dialog in ui file.");
+
+        for id in ["new_room_name", "new_room_topic", "new_room_alias", "new_room_invite"].iter() {
+            let entry = self.gtk_builder
+                .get_object::<gtk::Entry>(id)
+                .expect("Can't find new room entry in ui file.");
+            entry.set_text("");
+            entry.get_style_context().unwrap().remove_class("error");
+            entry.set_tooltip_text(None);
+        }
+
+        dialog.show();
+    }
+
+    /// Creates the room with the options of the new room dialog. Returns
+    /// false if the options aren't valid, so the dialog stays open.
+    pub fn create_room(&self) -> bool {
+        let name = self.gtk_builder
+            .get_object::<gtk::Entry>("new_room_name")
+            .expect("Can't find new_room_name in ui file.");
+        let topic = self.gtk_builder
+            .get_object::<gtk::Entry>("new_room_topic")
+            .expect("Can't find new_room_topic in ui file.");
+        let alias = self.gtk_builder
+            .get_object::<gtk::Entry>("new_room_alias")
+            .expect("Can't find new_room_alias in ui file.");
+        let invite = self.gtk_builder
+            .get_object::<gtk::Entry>("new_room_invite")
+            .expect("Can't find new_room_invite in ui file.");
+        let visibility = self.gtk_builder
+            .get_object::<gtk::ComboBoxText>("new_room_visibility")
+            .expect("Can't find new_room_visibility in ui file.");
+        let preset = self.gtk_builder
+            .get_object::<gtk::ComboBoxText>("new_room_preset")
+            .expect("Can't find new_room_preset in ui file.");
+
+        // the alias is only the local part, "#alias:server" is also valid
+        let alias = alias.get_text().unwrap_or_default();
+        let alias = alias.trim().trim_left_matches('#').split(':').next().unwrap_or("");
+
+        let invites: Vec<String> = invite.get_text().unwrap_or_default()
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|u| !u.is_empty())
+            .map(|u| strn!(u))
+            .collect();
+
+        // the server rejects the whole room if one of the users is wrong
+        let wrong: Vec<&str> = invites.iter()
+            .filter(|u| !is_uid(u))
+            .map(|u| u.as_str())
+            .collect();
+        if !wrong.is_empty() {
+            invite.get_style_context().unwrap().add_class("error");
+            invite.set_tooltip_text(&format!("Not a valid user id: {}", wrong.join(", "))[..]);
+            invite.grab_focus();
+            return false;
+        }
+        invite.get_style_context().unwrap().remove_class("error");
+        invite.set_tooltip_text(None);
+
+        let room = NewRoom {
+            name: name.get_text().unwrap_or_default(),
+            topic: topic.get_text().unwrap_or_default(),
+            alias: strn!(alias),
+            public: visibility.get_active_id().unwrap_or_default() == "public",
+            preset: preset.get_active_id().unwrap_or(strn!("private_chat")),
+            invites: invites,
+        };
+        self.backend.send(BKCommand::CreateRoom(room)).unwrap();
+
+        true
+    }
+
+    pub fn show_invite_dialog(&self) {
+        let dialog = self.gtk_builder
+            .get_object::<gtk::Dialog>("invite_dialog")
+            .expect("Can't find invite_dialog in ui file.");
+        let entry = self.gtk_builder
+            .get_object::<gtk::SearchEntry>("invite_entry")
+            .expect("Can't find invite_entry in ui file.");
+        let button = self.gtk_builder
+            .get_object::<gtk::Button>("invite_button")
+            .expect("Can't find invite_button in ui file.");
+        let list = self.gtk_builder
+            .get_object::<gtk::ListBox>("invite_list")
+            .expect("Can't find invite_list in ui file.");
+
+        self.invite_selected.lock().unwrap().clear();
+        for ch in list.get_children().iter() {
+            list.remove(ch);
+        }
+        button.set_label("Invite");
+        button.set_sensitive(false);
+
+        // the text is changed outside of this call, because the entry
+        // search-changed signal calls to search_invite_users
+        gtk::timeout_add(50, move || {
+            entry.set_text("");
+            entry.grab_focus();
+            gtk::Continue(false)
+        });
+
+        dialog.show();
+    }
+
+    pub fn search_invite_users(&self, term: String) {
+        match term.trim() {
+            "" => { self.set_invite_results(String::new(), vec![]); }
+            t => { self.backend.send(BKCommand::UserSearch(strn!(t))).unwrap(); }
+        }
+    }
+
+    /// Shows the users found in the directory, and the user id written in
+    /// the entry if it's a valid one. The users that are already in the
+    /// room aren't shown. The results of an older @term are ignored.
+    pub fn set_invite_results(&self, term: String, users: Vec<Member>) {
+        let entry = self.gtk_builder
+            .get_object::<gtk::SearchEntry>("invite_entry")
+            .expect("Can't find invite_entry in ui file.");
+        if entry.get_text().unwrap_or_default().trim() != term {
+            return;
+        }
+        let button = self.gtk_builder
+            .get_object::<gtk::Button>("invite_button")
+            .expect("Can't find invite_button in ui file.");
+        let list = self.gtk_builder
+            .get_object::<gtk::ListBox>("invite_list")
+            .expect("Can't find invite_list in ui file.");
+
+        for ch in list.get_children().iter() {
+            list.remove(ch);
+        }
+
+        let text = strn!(entry.get_text().unwrap_or_default().trim());
+        let mut users = users;
+        if is_uid(&text) && !users.iter().any(|u| u.uid == text) {
+            users.insert(0, Member { alias: String::new(), uid: text, avatar: String::new() });
+        }
+
+        for u in users {
+            if u.uid.is_empty() || self.members.contains_key(&u.uid) ||
+               self.invited.contains_key(&u.uid) {
+                continue;
             }
+
+            let label = if u.alias.is_empty() {
+                u.uid.clone()
+            } else {
+                format!("{} ({})", u.alias, u.uid)
+            };
+            let check = gtk::CheckButton::new_with_label(&label);
+            check.set_margin_left(6);
+            check.set_margin_top(3);
+            check.set_margin_bottom(3);
+
+            let selected = self.invite_selected.clone();
+            check.set_active(selected.lock().unwrap().contains(&u.uid));
+
+            let btn = button.clone();
+            let uid = u.uid.clone();
+            check.connect_toggled(move |c| {
+                let mut s = selected.lock().unwrap();
+                s.retain(|x| *x != uid);
+                if c.get_active() {
+                    s.push(uid.clone());
+                }
+                btn.set_sensitive(!s.is_empty());
+                match s.len() {
+                    0 => btn.set_label("Invite"),
+                    n => btn.set_label(&format!("Invite ({})", n)),
+                };
+            });
+
+            list.add(&check);
+        }
+
+        list.show_all();
+    }
+
+    pub fn invite_selected_users(&self) {
+        let room = self.active_room.clone();
+        let users: Vec<String> = self.invite_selected.lock().unwrap().drain(..).collect();
+
+        for uid in users {
+            self.backend.send(BKCommand::Invite(room.clone(), uid)).unwrap();
         }
     }
 
+    pub fn leave_active_room(&mut self) {
+        let r = self.active_room.clone();
+        self.backend.send(BKCommand::LeaveRoom(r.clone())).unwrap();
+        self.remove_room(&r);
+    }
+
     pub fn change_room_config(&mut self) {
         let name = self.gtk_builder
             .get_object::<gtk::Entry>("room_name_entry")
@@ -1080,6 +3033,10 @@ impl AppOp {
                     self.backend.send(command).unwrap();
                 }
             }
+            if let Some(changes) = self.permissions_changes() {
+                let command = BKCommand::SetPowerLevels(r.id.clone(), changes);
+                self.backend.send(command).unwrap();
+            }
         }
     }
 
@@ -1088,10 +3045,10 @@ impl AppOp {
             .get_object("rooms_tree_store")
             .expect("Couldn't find rooms_tree_store in ui file.");
 
-        {
-            let r = self.rooms.get_mut(&roomid).unwrap();
-            r.name = name.clone();
-        }
+        match self.rooms.get_mut(&roomid) {
+            Some(r) => { r.name = name.clone(); }
+            None => { return; }
+        };
 
         if roomid == self.active_room {
             self.gtk_builder
@@ -1099,26 +3056,17 @@ impl AppOp {
                 .expect("Can't find room_name in ui file.")
                 .set_text(&name);
         }
-
-        if let Some(iter) = store.get_iter_first() {
-            loop {
-                let v1 = store.get_value(&iter, 1);
-                let id: &str = v1.get().unwrap();
-                if id == roomid {
-                    store.set_value(&iter, 0, &gtk::Value::from(&name));
-                }
-                if !store.iter_next(&iter) {
-                    break;
-                }
-            }
-        }
+
+        if let Some(iter) = self.find_room_iter(&store, &roomid) {
+            store.set_value(&iter, 0, &gtk::Value::from(&name));
+        }
     }
 
     pub fn room_topic_change(&mut self, roomid: String, topic: String) {
-        {
-            let r = self.rooms.get_mut(&roomid).unwrap();
-            r.topic = topic.clone();
-        }
+        match self.rooms.get_mut(&roomid) {
+            Some(r) => { r.topic = topic.clone(); }
+            None => { return; }
+        };
 
         if roomid == self.active_room {
             let t = self.gtk_builder
@@ -1130,6 +3078,12 @@ impl AppOp {
         }
     }
 
+    pub fn room_alias_change(&mut self, roomid: String, alias: String) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.alias = alias;
+        }
+    }
+
     pub fn new_room_avatar(&self, roomid: String) {
         self.backend.send(BKCommand::GetRoomAvatar(roomid)).unwrap();
     }
@@ -1147,22 +3101,36 @@ impl AppOp {
             .get_object::<gtk::ListStore>("members_store")
             .expect("Can't find members_store in ui file.");
 
-        let sender = ev.sender.clone();
+        // the member is the state_key, the sender can be other user, for
+        // example in kicks or invites
+        let sender = match ev.state_key {
+            ref k if k.is_empty() => ev.sender.clone(),
+            ref k => k.clone(),
+        };
         match ev.content["membership"].as_str() {
             Some("leave") => {
-                self.members.remove(&sender);
-                if let Some(iter) = store.get_iter_first() {
-                    loop {
-                        let v1 = store.get_value(&iter, 1);
-                        let id: &str = v1.get().unwrap();
-                        if id == sender {
-                            store.remove(&iter);
-                        }
-                        if !store.iter_next(&iter) {
-                            break;
-                        }
-                    }
-                }
+                self.remove_member(&sender);
+            }
+            Some("ban") => {
+                let alias = match self.members.get(&sender) {
+                    Some(m) => m.alias.clone(),
+                    None => strn!(ev.content["displayname"].as_str().unwrap_or("")),
+                };
+                let m = Member {
+                    avatar: String::new(),
+                    alias: alias,
+                    uid: sender.clone(),
+                };
+                self.remove_member(&sender);
+                self.add_banned_member(m);
+            }
+            Some("invite") => {
+                let m = Member {
+                    avatar: strn!(ev.content["avatar_url"].as_str().unwrap_or("")),
+                    alias: strn!(ev.content["displayname"].as_str().unwrap_or("")),
+                    uid: sender.clone(),
+                };
+                self.add_invited_member(m);
             }
             Some("join") => {
                 let m = Member {
@@ -1170,7 +3138,20 @@ impl AppOp {
                     alias: strn!(ev.content["displayname"].as_str().unwrap_or("")),
                     uid: sender.clone(),
                 };
-                self.add_room_member(m);
+
+                if self.invited.contains_key(&sender) {
+                    self.remove_member(&sender);
+                }
+
+                // if it's a known member this is a profile change
+                if self.members.contains_key(&sender) {
+                    if let Some((iter, _)) = self.find_member_row(&store, &sender) {
+                        store.set_value(&iter, 0, &gtk::Value::from(&m.get_alias()));
+                    }
+                    self.members.insert(sender.clone(), m);
+                } else {
+                    self.add_room_member(m);
+                }
             }
             Some(_) => {
                 // ignoring other memberships
@@ -1404,8 +3385,41 @@ impl App {
 
         self.connect_directory();
         self.connect_room_config();
+        self.connect_new_room();
+        self.connect_invite_buttons();
 
         self.connect_search();
+        self.connect_presence();
+    }
+
+    fn connect_presence(&self) {
+        let window: gtk::Window = self.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+        let btn = self.gtk_builder
+            .get_object::<gtk::Button>("status_button")
+            .expect("Can't find status_button in ui file.");
+
+        let mut op = self.op.clone();
+        btn.connect_clicked(move |_| { op.lock().unwrap().set_own_presence(); });
+
+        op = self.op.clone();
+        window.connect_key_press_event(move |_, _| {
+            op.lock().unwrap().user_activity();
+            Inhibit(false)
+        });
+
+        op = self.op.clone();
+        window.connect_focus_in_event(move |_, _| {
+            op.lock().unwrap().user_activity();
+            Inhibit(false)
+        });
+
+        op = self.op.clone();
+        gtk::timeout_add(30000, move || {
+            op.lock().unwrap().check_idle();
+            gtk::Continue(true)
+        });
     }
 
     fn connect_room_config(&self) {
@@ -1469,6 +3483,53 @@ impl App {
         });
     }
 
+    fn connect_new_room(&self) {
+        let btn = self.gtk_builder
+            .get_object::<gtk::Button>("new_room_button")
+            .expect("Can't find new_room_button in ui file.");
+        let mut op = self.op.clone();
+        btn.connect_clicked(move |_| {
+            op.lock().unwrap().show_new_room_dialog();
+        });
+
+        let dialog = self.gtk_builder
+            .get_object::<gtk::Dialog>("new_room_dialog")
+            .expect("Can't find new_room_dialog in ui file.");
+
+        let cancel = self.gtk_builder
+            .get_object::<gtk::Button>("new_room_cancel")
+            .expect("Can't find new_room_cancel in ui file.");
+        let d = dialog.clone();
+        cancel.connect_clicked(move |_| {
+            d.hide();
+        });
+
+        let create = self.gtk_builder
+            .get_object::<gtk::Button>("new_room_create")
+            .expect("Can't find new_room_create in ui file.");
+        let d = dialog.clone();
+        op = self.op.clone();
+        create.connect_clicked(move |_| {
+            if op.lock().unwrap().create_room() {
+                d.hide();
+            }
+        });
+    }
+
+    fn connect_invite_buttons(&self) {
+        let accept = self.gtk_builder
+            .get_object::<gtk::Button>("invite_accept")
+            .expect("Can't find invite_accept in ui file.");
+        let decline = self.gtk_builder
+            .get_object::<gtk::Button>("invite_decline")
+            .expect("Can't find invite_decline in ui file.");
+
+        let op = self.op.clone();
+        accept.connect_clicked(move |_| { op.lock().unwrap().accept_inv(); });
+        let op = self.op.clone();
+        decline.connect_clicked(move |_| { op.lock().unwrap().reject_inv(); });
+    }
+
     fn connect_directory(&self) {
         let btn = self.gtk_builder
             .get_object::<gtk::Button>("directory_search_button")
@@ -1511,10 +3572,29 @@ impl App {
             .get_object::<gtk::ScrolledWindow>("messages_scroll")
             .expect("Can't find message_scroll in ui file.");
 
-        let op = self.op.clone();
+        let mut op = self.op.clone();
         s.connect_edge_overshot(move |_, dir| if dir == gtk::PositionType::Top {
             op.lock().unwrap().load_more_messages();
         });
+
+        if let Some(adj) = s.get_vadjustment() {
+            op = self.op.clone();
+            adj.connect_value_changed(move |_| {
+                op.lock().unwrap().check_fully_read();
+            });
+            op = self.op.clone();
+            adj.connect_changed(move |_| {
+                op.lock().unwrap().check_fully_read();
+            });
+        }
+
+        let jump = self.gtk_builder
+            .get_object::<gtk::Button>("jump_unread_button")
+            .expect("Can't find jump_unread_button in ui file.");
+        op = self.op.clone();
+        jump.connect_clicked(move |_| {
+            op.lock().unwrap().jump_to_unread();
+        });
     }
 
     fn connect_send(&self) {
@@ -1522,16 +3602,90 @@ impl App {
             .get_object("msg_entry")
             .expect("Couldn't find msg_entry in ui file.");
 
+        // only the joined members are completed, not the groups of the
+        // invited and banned users
+        let completion: gtk::TreeModelFilter = self.gtk_builder
+            .get_object("members_completion_model")
+            .expect("Couldn't find members_completion_model in ui file.");
+        completion.set_visible_column(5);
+
         let mut op = self.op.clone();
         msg_entry.connect_activate(move |entry| if let Some(text) = entry.get_text() {
-            op.lock().unwrap().send_message(text);
-            entry.set_text("");
+            let done = op.lock().unwrap().run_composer(text);
+            if done {
+                entry.set_text("");
+            }
         });
 
         op = self.op.clone();
         msg_entry.connect_paste_clipboard(move |_| {
             op.lock().unwrap().paste();
         });
+
+        op = self.op.clone();
+        msg_entry.connect_key_press_event(move |entry, ev| {
+            if ev.get_keyval() == gdk::enums::key::Tab {
+                let text = entry.get_text().unwrap_or_default();
+                if text.starts_with('/') && !text.starts_with("//") && !text.contains(' ') {
+                    let names = command::complete(&text[1..]);
+                    match names.len() {
+                        0 => {
+                            if let Err(usage) = command::parse(&text) {
+                                op.lock().unwrap().show_command_error(&usage);
+                            }
+                        }
+                        1 => {
+                            entry.set_text(&format!("/{} ", names[0]));
+                            entry.set_position(-1);
+                        }
+                        _ => {
+                            let prefix = command::common_prefix(&names);
+                            entry.set_text(&format!("/{}", prefix));
+                            entry.set_position(-1);
+                            let all: Vec<String> = names.iter().map(|n| format!("/{}", n)).collect();
+                            op.lock().unwrap().show_command_error(&all.join("  "));
+                        }
+                    }
+                    return Inhibit(true);
+                }
+            }
+
+            if ev.get_keyval() == gdk::enums::key::Escape {
+                op.lock().unwrap().cancel_reply();
+                let editing = op.lock().unwrap().cancel_edit();
+                if editing {
+                    entry.set_text("");
+                }
+            }
+            Inhibit(false)
+        });
+
+        let reply_cancel = self.gtk_builder
+            .get_object::<gtk::Button>("reply_cancel")
+            .expect("Can't find reply_cancel in ui file.");
+        op = self.op.clone();
+        reply_cancel.connect_clicked(move |_| {
+            op.lock().unwrap().cancel_reply();
+        });
+
+        op = self.op.clone();
+        msg_entry.connect_changed(move |entry| {
+            let typing = match entry.get_text() {
+                Some(ref text) => !text.is_empty(),
+                None => false,
+            };
+            op.lock().unwrap().typing_changed(typing);
+            op.lock().unwrap().update_markdown_preview();
+            op.lock().unwrap().hide_command_error();
+        });
+
+        let preview_button = self.gtk_builder
+            .get_object::<gtk::ToggleButton>("markdown_preview_button")
+            .expect("Can't find markdown_preview_button in ui file.");
+        op = self.op.clone();
+        preview_button.connect_toggled(move |_| {
+            op.lock().unwrap().update_markdown_preview();
+        });
     }
 
     fn connect_attach(&self) {
@@ -1644,7 +3798,49 @@ impl App {
         members.connect_row_activated(move |view, path, _| {
             let iter = view.get_model().unwrap().get_iter(path).unwrap();
             let id = view.get_model().unwrap().get_value(&iter, 1);
-            op.lock().unwrap().member_clicked(id.get().unwrap());
+            let uid: String = id.get().unwrap();
+
+            // the invited group row
+            if uid.is_empty() {
+                return;
+            }
+
+            if let Some(menu) = build_member_menu(&op, &uid) {
+                menu.popup_easy(0, gtk::get_current_event_time());
+            }
+        });
+
+        let invite: gtk::Button = self.gtk_builder
+            .get_object("members_invite_button")
+            .expect("Couldn't find members_invite_button in ui file.");
+        let op = self.op.clone();
+        invite.connect_clicked(move |_| { op.lock().unwrap().show_invite_dialog(); });
+
+        let dialog = self.gtk_builder
+            .get_object::<gtk::Dialog>("invite_dialog")
+            .expect("Can't find invite_dialog in ui file.");
+        let entry = self.gtk_builder
+            .get_object::<gtk::SearchEntry>("invite_entry")
+            .expect("Can't find invite_entry in ui file.");
+        let op = self.op.clone();
+        entry.connect_search_changed(move |e| {
+            op.lock().unwrap().search_invite_users(e.get_text().unwrap_or_default());
+        });
+
+        let cancel: gtk::Button = self.gtk_builder
+            .get_object("invite_cancel")
+            .expect("Couldn't find invite_cancel in ui file.");
+        let d = dialog.clone();
+        cancel.connect_clicked(move |_| { d.hide(); });
+
+        let btn: gtk::Button = self.gtk_builder
+            .get_object("invite_button")
+            .expect("Couldn't find invite_button in ui file.");
+        let d = dialog.clone();
+        let op = self.op.clone();
+        btn.connect_clicked(move |_| {
+            op.lock().unwrap().invite_selected_users();
+            d.hide();
         });
 
         let mbutton: gtk::Button = self.gtk_builder
@@ -1683,120 +3879,373 @@ fn sync_loop(op: Arc<Mutex<AppOp>>) {
 
 fn backend_loop(op: Arc<Mutex<AppOp>>, rx: Receiver<BKResponse>) {
     gtk::timeout_add(500, move || {
-        let recv = rx.try_recv();
-        match recv {
-            Ok(BKResponse::Token(uid, _)) => {
-                op.lock().unwrap().set_uid(&uid);
-                op.lock().unwrap().set_username(&uid);
-                op.lock().unwrap().get_username();
-                op.lock().unwrap().sync();
+        // a sync sends lots of responses, so we process all that we have
+        loop {
+            let recv = rx.try_recv();
+            match recv {
+                Ok(BKResponse::Token(uid, _)) => {
+                    op.lock().unwrap().set_uid(&uid);
+                    op.lock().unwrap().set_username(&uid);
+                    op.lock().unwrap().get_username();
+                    op.lock().unwrap().resume_sync();
+
+                    op.lock().unwrap().init_protocols();
+                }
+                Ok(BKResponse::Name(username)) => {
+                    op.lock().unwrap().set_username(&username);
+                }
+                Ok(BKResponse::Avatar(path)) => {
+                    op.lock().unwrap().set_avatar(&path);
+                }
+                Ok(BKResponse::Sync(since)) => {
+                    println!("SYNC");
+                    op.lock().unwrap().synced(since);
+                }
+                Ok(BKResponse::SyncFilter(id, def)) => {
+                    op.lock().unwrap().set_sync_filter(id, def);
+                }
+                Ok(BKResponse::Rooms(rooms, default)) => {
+                    op.lock().unwrap().set_rooms(rooms, default);
+                }
+                Ok(BKResponse::NewRooms(rooms)) => {
+                    op.lock().unwrap().new_rooms(rooms);
+                }
+                Ok(BKResponse::LeftRoom(roomid, ev)) => {
+                    op.lock().unwrap().left_room(roomid, ev);
+                }
+                Ok(BKResponse::RoomDetail(room, key, value)) => {
+                    op.lock().unwrap().set_room_detail(room, key, value);
+                }
+                Ok(BKResponse::RoomAvatar(room, avatar)) => {
+                    op.lock().unwrap().set_room_avatar(room, avatar);
+                }
+                Ok(BKResponse::RoomMessages(msgs)) => {
+                    op.lock().unwrap().show_room_messages(msgs, false);
+                }
+                Ok(BKResponse::RoomNotifications(roomid, n, h)) => {
+                    op.lock().unwrap().room_notifications(roomid, n, h);
+                }
+                Ok(BKResponse::RoomTyping(roomid, users)) => {
+                    op.lock().unwrap().set_room_typing(roomid, users);
+                }
+                Ok(BKResponse::RoomReceipts(roomid, receipts)) => {
+                    op.lock().unwrap().room_receipts(roomid, receipts);
+                }
+                Ok(BKResponse::GetFullyReadError(roomid, _)) => {
+                    op.lock().unwrap().fully_read_error(roomid);
+                }
+                Ok(BKResponse::RoomFullyRead(roomid, evid)) => {
+                    op.lock().unwrap().set_room_fully_read(roomid, evid);
+                }
+                Ok(BKResponse::UserPresence(uid, presence, status)) => {
+                    op.lock().unwrap().set_user_presence(uid, presence, status);
+                }
+                Ok(BKResponse::RoomPowerLevels(roomid, pl)) => {
+                    op.lock().unwrap().room_power_levels(roomid, pl);
+                }
+                Ok(BKResponse::RoomRedaction(roomid, evid)) => {
+                    op.lock().unwrap().room_redaction(roomid, evid);
+                }
+                Ok(BKResponse::DirectRooms(direct)) => {
+                    op.lock().unwrap().set_direct_rooms(direct);
+                }
+                Ok(BKResponse::RoomReaction(roomid, reaction)) => {
+                    op.lock().unwrap().room_reaction(roomid, reaction);
+                }
+                Ok(BKResponse::RoomMessagesInit(msgs)) => {
+                    op.lock().unwrap().show_room_messages(msgs, true);
+                }
+                Ok(BKResponse::RoomMessagesTo(msgs)) => {
+                    op.lock().unwrap().show_room_messages_top(msgs);
+                }
+                Ok(BKResponse::RoomGapFilled(roomid, msgid, msgs, token)) => {
+                    op.lock().unwrap().room_gap_filled(roomid, msgid, msgs, token);
+                }
+                Ok(BKResponse::RoomTimelineGap(roomid, token)) => {
+                    op.lock().unwrap().room_timeline_gap(roomid, token);
+                }
+                Ok(BKResponse::RoomGapFilledError(roomid, msgid)) => {
+                    op.lock().unwrap().room_gap_fill_error(roomid, msgid);
+                }
+                Ok(BKResponse::RoomMembers(members)) => {
+                    let mut ms = members;
+                    ms.sort_by(|x, y| {
+                        x.get_alias().to_lowercase().cmp(&y.get_alias().to_lowercase())
+                    });
+                    for m in ms {
+                        op.lock().unwrap().add_room_member(m);
+                    }
+                }
+                Ok(BKResponse::RoomInvited(members)) => {
+                    for m in members {
+                        op.lock().unwrap().add_invited_member(m);
+                    }
+                }
+                Ok(BKResponse::RoomBanned(members)) => {
+                    for m in members {
+                        op.lock().unwrap().add_banned_member(m);
+                    }
+                }
+                Ok(BKResponse::UserSearch(term, users)) => {
+                    op.lock().unwrap().set_invite_results(term, users);
+                }
+                Ok(BKResponse::UserSearchError(_)) => {
+                    println!("Can't search users");
+                }
+                Ok(BKResponse::DirectRoomsError(_)) => {
+                    println!("Can't get the direct chats");
+                }
+                Ok(BKResponse::RoomBatchEnd(roomid, batch)) => {
+                    op.lock().unwrap().room_batch_end(roomid, batch);
+                }
+                Ok(BKResponse::SendMsg) => {
+                    op.lock().unwrap().sync();
+                }
+                Ok(BKResponse::DirectoryProtocols(protocols)) => {
+                    op.lock().unwrap().set_protocols(protocols);
+                }
+                Ok(BKResponse::DirectorySearch(rooms)) => {
+                    for room in rooms {
+                        op.lock().unwrap().set_directory_room(room);
+                    }
+                }
+                Ok(BKResponse::JoinRoom) => {
+                    op.lock().unwrap().reload_rooms();
+                }
+                Ok(BKResponse::LeaveRoom) => { }
+                Ok(BKResponse::Invite) => { }
+                Ok(BKResponse::Kick) => { }
+                Ok(BKResponse::Ban) => { }
+                Ok(BKResponse::Unban) => { }
+                Ok(BKResponse::SetRoomName) => { }
+                Ok(BKResponse::SetRoomTopic) => { }
+                Ok(BKResponse::SetRoomAvatar) => { }
+                Ok(BKResponse::SetPowerLevels) => { }
+                Ok(BKResponse::MarkedAsRead(r, _)) => {
+                    op.lock().unwrap().set_room_notifications(&r, 0, 0);
+                }
 
-                op.lock().unwrap().init_protocols();
-            }
-            Ok(BKResponse::Name(username)) => {
-                op.lock().unwrap().set_username(&username);
-            }
-            Ok(BKResponse::Avatar(path)) => {
-                op.lock().unwrap().set_avatar(&path);
-            }
-            Ok(BKResponse::Sync) => {
-                println!("SYNC");
-                op.lock().unwrap().syncing = false;
-            }
-            Ok(BKResponse::Rooms(rooms, default)) => {
-                op.lock().unwrap().set_rooms(rooms, default);
-            }
-            Ok(BKResponse::RoomDetail(room, key, value)) => {
-                op.lock().unwrap().set_room_detail(room, key, value);
-            }
-            Ok(BKResponse::RoomAvatar(room, avatar)) => {
-                op.lock().unwrap().set_room_avatar(room, avatar);
-            }
-            Ok(BKResponse::RoomMessages(msgs)) => {
-                op.lock().unwrap().show_room_messages(msgs, false);
-            }
-            Ok(BKResponse::RoomMessagesInit(msgs)) => {
-                op.lock().unwrap().show_room_messages(msgs, true);
-            }
-            Ok(BKResponse::RoomMessagesTo(msgs)) => {
-                op.lock().unwrap().show_room_messages_top(msgs);
-            }
-            Ok(BKResponse::RoomMembers(members)) => {
-                let mut ms = members;
-                ms.sort_by(|x, y| {
-                    x.get_alias().to_lowercase().cmp(&y.get_alias().to_lowercase())
-                });
-                for m in ms {
-                    op.lock().unwrap().add_room_member(m);
+                Ok(BKResponse::RoomName(roomid, name)) => {
+                    op.lock().unwrap().room_name_change(roomid, name);
                 }
-            }
-            Ok(BKResponse::RoomBatchEnd(roomid, batch)) => {
-                op.lock().unwrap().room_batch_end(roomid, batch);
-            }
-            Ok(BKResponse::SendMsg) => {
-                op.lock().unwrap().sync();
-            }
-            Ok(BKResponse::DirectoryProtocols(protocols)) => {
-                op.lock().unwrap().set_protocols(protocols);
-            }
-            Ok(BKResponse::DirectorySearch(rooms)) => {
-                for room in rooms {
-                    op.lock().unwrap().set_directory_room(room);
+                Ok(BKResponse::RoomTopic(roomid, topic)) => {
+                    op.lock().unwrap().room_topic_change(roomid, topic);
+                }
+                Ok(BKResponse::RoomAlias(roomid, alias)) => {
+                    op.lock().unwrap().room_alias_change(roomid, alias);
+                }
+                Ok(BKResponse::NewRoomAvatar(roomid)) => {
+                    op.lock().unwrap().new_room_avatar(roomid);
+                }
+                Ok(BKResponse::RoomMemberEvent(ev)) => {
+                    op.lock().unwrap().room_member_event(ev);
+                }
+                Ok(BKResponse::Media(fname)) => {
+                    Command::new("xdg-open")
+                                .arg(&fname)
+                                .spawn()
+                                .expect("failed to execute process");
+                }
+                Ok(BKResponse::AttachedFile(msg)) => {
+                    op.lock().unwrap().add_tmp_room_message(&msg);
+                }
+                Ok(BKResponse::SearchEnd) => {
+                    op.lock().unwrap().search_end();
+                }
+                Ok(BKResponse::NotificationClicked(msg)) => {
+                    op.lock().unwrap().notification_cliked(msg);
+                }
+                Ok(BKResponse::EditClicked(msg)) => {
+                    op.lock().unwrap().edit_clicked(msg);
+                }
+                Ok(BKResponse::ReplyClicked(msg)) => {
+                    op.lock().unwrap().reply_clicked(msg);
+                }
+                Ok(BKResponse::QuoteClicked(roomid, evid)) => {
+                    op.lock().unwrap().quote_clicked(roomid, evid);
                 }
-            }
-            Ok(BKResponse::JoinRoom) => {
-                op.lock().unwrap().reload_rooms();
-            }
-            Ok(BKResponse::LeaveRoom) => { }
-            Ok(BKResponse::SetRoomName) => { }
-            Ok(BKResponse::SetRoomTopic) => { }
-            Ok(BKResponse::SetRoomAvatar) => { }
-            Ok(BKResponse::MarkedAsRead(r, _)) => {
-                op.lock().unwrap().update_room_notifications(&r, |_| 0);
-            }
 
-            Ok(BKResponse::RoomName(roomid, name)) => {
-                op.lock().unwrap().room_name_change(roomid, name);
-            }
-            Ok(BKResponse::RoomTopic(roomid, topic)) => {
-                op.lock().unwrap().room_topic_change(roomid, topic);
-            }
-            Ok(BKResponse::NewRoomAvatar(roomid)) => {
-                op.lock().unwrap().new_room_avatar(roomid);
-            }
-            Ok(BKResponse::RoomMemberEvent(ev)) => {
-                op.lock().unwrap().room_member_event(ev);
-            }
-            Ok(BKResponse::Media(fname)) => {
-                Command::new("xdg-open")
-                            .arg(&fname)
-                            .spawn()
-                            .expect("failed to execute process");
-            }
-            Ok(BKResponse::AttachedFile(msg)) => {
-                op.lock().unwrap().add_tmp_room_message(&msg);
-            }
-            Ok(BKResponse::SearchEnd) => {
-                op.lock().unwrap().search_end();
-            }
-            Ok(BKResponse::NotificationClicked(msg)) => {
-                op.lock().unwrap().notification_cliked(msg);
-            }
+                // errors
+                Ok(BKResponse::LoginError(_)) => {
+                    op.lock().unwrap().show_error("Can't login, try again");
+                },
+                Ok(BKResponse::JoinRoomError(_)) => {
+                    op.lock().unwrap().show_error("Can't join to the room");
+                }
+                Ok(BKResponse::SetUserNameError(_)) => {
+                    op.lock().unwrap().show_error("Can't change the display name");
+                }
+                Ok(BKResponse::InviteError(_)) => {
+                    op.lock().unwrap().show_error("Can't invite the user");
+                }
+                Ok(BKResponse::KickError(_)) => {
+                    op.lock().unwrap().show_error("Can't kick the user");
+                }
+                Ok(BKResponse::BanError(_)) => {
+                    op.lock().unwrap().show_error("Can't ban the user");
+                }
+                Ok(BKResponse::SetPowerLevelsError(_)) => {
+                    op.lock().unwrap().show_error("Can't change the room permissions");
+                }
+                Ok(BKResponse::UnbanError(_)) => {
+                    op.lock().unwrap().show_error("Can't unban the user");
+                }
+                Ok(BKResponse::CreateRoomError(_)) => {
+                    op.lock().unwrap().show_error("Can't create the room");
+                }
+                Ok(BKResponse::DirectChatError(_)) => {
+                    op.lock().unwrap().show_error("Can't create the direct chat");
+                }
+                Ok(BKResponse::SyncError(_)) => {
+                    println!("SYNC Error");
+                    op.lock().unwrap().syncing = false;
+                }
+                Ok(err) => {
+                    println!("Query error: {:?}", err);
+                }
+                Err(_) => { break; }
+            };
+        }
 
-            // errors
-            Ok(BKResponse::LoginError(_)) => {
-                op.lock().unwrap().show_error("Can't login, try again");
-            },
-            Ok(BKResponse::SyncError(_)) => {
-                println!("SYNC Error");
-                op.lock().unwrap().syncing = false;
-            }
-            Ok(err) => {
-                println!("Query error: {:?}", err);
-            }
-            Err(_) => {}
+        gtk::Continue(true)
+    });
+}
+
+fn build_permission_title(title: &str) -> gtk::Label {
+    let label = gtk::Label::new("");
+    label.set_markup(&format!("<b>{}</b>", title));
+    label.set_halign(gtk::Align::Start);
+    label.set_margin_top(6);
+    label
+}
+
+/// A row of the permissions page with the power level of a member or the
+/// level needed for an action, and the role name of that level
+fn build_permission_row(label: &str, level: i32, max: i32, sensitive: bool) -> (gtk::Box, gtk::SpinButton) {
+    let row = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+    let name = gtk::Label::new(label);
+    name.set_halign(gtk::Align::Start);
+    name.set_ellipsize(pango::EllipsizeMode::End);
+
+    let role = gtk::Label::new(power_level_name(level).as_str());
+    role.get_style_context().unwrap().add_class("dim-label");
+
+    // we can't set levels over our own level, and the muted users can
+    // have negative levels
+    let lower = cmp::min(level, 0);
+    let upper = cmp::max(level, max);
+    let spin = gtk::SpinButton::new_with_range(lower as f64, upper as f64, 1.0);
+    spin.set_value(level as f64);
+    spin.set_sensitive(sensitive);
+    let r = role.clone();
+    spin.connect_value_changed(move |s| {
+        r.set_text(&power_level_name(s.get_value_as_int()));
+    });
+
+    row.pack_start(&name, true, true, 0);
+    row.pack_start(&role, false, false, 0);
+    row.pack_start(&spin, false, false, 0);
+
+    (row, spin)
+}
+
+fn power_level_name(level: i32) -> String {
+    match level {
+        l if l >= 100 => strn!("Admin"),
+        l if l >= 50 => strn!("Moderator"),
+        0 => strn!("Default"),
+        l => format!("Custom ({})", l),
+    }
+}
+
+/// The actions for a member of the room, None if there isn't any action,
+/// like for ourselves. The moderation actions are only enabled if our power
+/// level allows them.
+fn build_member_menu(op: &Arc<Mutex<AppOp>>, uid: &str) -> Option<gtk::Menu> {
+    let (window, room, backend, name, banned, invited, can_kick, can_ban) = {
+        let op = op.lock().unwrap();
+        if op.uid == uid {
+            return None;
+        }
+
+        let window: gtk::Window = op.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+        let name = match op.members.get(uid).or(op.invited.get(uid)).or(op.banned.get(uid)) {
+            Some(m) => m.get_alias(),
+            None => strn!(uid),
         };
+        let (kick, ban) = op.can_moderate(uid);
 
-        gtk::Continue(true)
+        (window, op.active_room.clone(), op.backend.clone(), name,
+         op.banned.contains_key(uid), op.invited.contains_key(uid), kick, ban)
+    };
+
+    let menu = gtk::Menu::new();
+
+    let dm = gtk::MenuItem::new_with_label("Send direct message");
+    let o = op.clone();
+    let u = strn!(uid);
+    dm.connect_activate(move |_| {
+        o.lock().unwrap().direct_chat(u.clone());
+    });
+    menu.append(&dm);
+
+    menu.append(&gtk::SeparatorMenuItem::new());
+
+    // kicking an invited user revokes the invitation
+    if !banned {
+        let label = if invited {
+            "Revoke invitation"
+        } else {
+            "Kick"
+        };
+        let kick = gtk::MenuItem::new_with_label(label);
+        kick.set_sensitive(can_kick);
+        let (w, r, b, u, n) = (window.clone(), room.clone(), backend.clone(), strn!(uid), name.clone());
+        kick.connect_activate(move |_| {
+            let (r, b, u) = (r.clone(), b.clone(), u.clone());
+            widgets::reason_dialog(&w, &format!("{} {}", label, n), label, move |reason| {
+                b.send(BKCommand::Kick(r.clone(), u.clone(), reason)).unwrap();
+            });
+        });
+        menu.append(&kick);
+    }
+
+    let label = if banned {
+        "Unban"
+    } else {
+        "Ban"
+    };
+    let ban = gtk::MenuItem::new_with_label(label);
+    ban.set_sensitive(can_ban);
+    let (w, r, b, u, n) = (window.clone(), room.clone(), backend.clone(), strn!(uid), name.clone());
+    ban.connect_activate(move |_| {
+        let (r, b, u) = (r.clone(), b.clone(), u.clone());
+        widgets::reason_dialog(&w, &format!("{} {}", label, n), label, move |reason| {
+            let cmd = if banned {
+                BKCommand::Unban(r.clone(), u.clone(), reason)
+            } else {
+                BKCommand::Ban(r.clone(), u.clone(), reason)
+            };
+            b.send(cmd).unwrap();
+        });
     });
+    menu.append(&ban);
+
+    menu.show_all();
+    Some(menu)
+}
+
+/// Keeps the edit until we load the edited message. Only the last edits
+/// that we've got in each room are kept, the edited messages could never be
+/// loaded.
+fn keep_pending_edit(pending: &mut HashMap<String, Vec<Message>>, edit: Message) {
+    let edits = pending.entry(edit.room.clone()).or_insert(vec![]);
+    edits.push(edit);
+    if edits.len() > MAX_PENDING_EDITS {
+        let n = edits.len() - MAX_PENDING_EDITS;
+        edits.drain(..n);
+    }
 }
@@ -9,8 +9,10 @@ use self::serde_json::Value as JsonValue;
 
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 use std::thread;
 use self::url::Url;
+use self::url::percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::RecvError;
@@ -22,7 +24,13 @@ use types::Message;
 use types::Member;
 use types::Protocol;
 use types::Room;
+use types::NewRoom;
 use types::Event;
+use types::Receipt;
+use types::PowerLevels;
+use types::PowerLevelsChanges;
+use types::Reaction;
+use types::SyncFilter;
 
 use std::fs::File;
 use std::io::prelude::*;
@@ -35,11 +43,16 @@ pub struct BackendData {
     access_token: String,
     server_url: String,
     since: String,
+    filter_id: String,
     msgid: i32,
     msgs_batch_start: String,
     msgs_batch_end: String,
     rooms_since: String,
     join_to_room: String,
+    // our presence, that's also set in each sync request
+    presence: String,
+    // the m.direct account data, user id -> direct chat rooms
+    direct: HashMap<String, Vec<String>>,
 }
 
 pub struct Backend {
@@ -60,8 +73,13 @@ pub enum BKCommand {
     GetAvatar,
     Sync,
     SyncForced,
+    SetSince(String),
+    SetDirect(HashMap<String, Vec<String>>),
+    GetDirect,
+    SetFilter(String, String),
     GetRoomMessagesTo(String),
     GetRoomMessages(String),
+    FillGap(String, String, String, String),
     GetRoomAvatar(String),
     GetThumbAsync(String, Sender<String>),
     GetMedia(String),
@@ -73,7 +91,15 @@ pub enum BKCommand {
     DirectorySearch(String, String, bool),
     JoinRoom(String),
     MarkAsRead(String, String),
+    SendTyping(String, bool),
+    GetFullyRead(String),
+    SetPresence(String, String),
+    Redact(String, String, String),
+    SendReaction(String, String, String),
+    SetFullyRead(String, String),
     LeaveRoom(String),
+    AcceptInv(String, Option<String>),
+    RejectInv(String),
     SetRoomName(String, String),
     SetRoomTopic(String, String),
     SetRoomAvatar(String, String),
@@ -81,6 +107,19 @@ pub enum BKCommand {
     AttachImage(String, Vec<u8>),
     Search(String, Option<String>),
     NotifyClicked(Message),
+    EditClicked(Message),
+    ReplyClicked(Message),
+    QuoteClicked(String, String),
+    GetMessageAsync(String, String, Sender<Message>),
+    SetUserName(String),
+    Invite(String, String),
+    Kick(String, String, String),
+    Ban(String, String, String),
+    Unban(String, String, String),
+    DirectChat(String),
+    CreateRoom(NewRoom),
+    UserSearch(String),
+    SetPowerLevels(String, PowerLevelsChanges),
 }
 
 #[derive(Debug)]
@@ -88,32 +127,58 @@ pub enum BKResponse {
     Token(String, String),
     Name(String),
     Avatar(String),
-    Sync,
+    Sync(String),
+    SyncFilter(String, String),
     Rooms(Vec<Room>, Option<Room>),
+    NewRooms(Vec<Room>),
+    LeftRoom(String, Option<Event>),
     RoomDetail(String, String, String),
     RoomAvatar(String, String),
     NewRoomAvatar(String),
     RoomMemberEvent(Event),
     RoomMessages(Vec<Message>),
+    RoomNotifications(String, i32, i32),
+    RoomTyping(String, Vec<String>),
+    RoomReceipts(String, Vec<Receipt>),
+    RoomFullyRead(String, String),
+    UserPresence(String, String, String),
+    RoomPowerLevels(String, PowerLevels),
+    RoomRedaction(String, String),
+    RoomReaction(String, Reaction),
+    DirectRooms(HashMap<String, Vec<String>>),
+    UserSearch(String, Vec<Member>),
     RoomMessagesInit(Vec<Message>),
     RoomMessagesTo(Vec<Message>),
+    RoomGapFilled(String, String, Vec<Message>, Option<String>),
+    RoomTimelineGap(String, String),
     RoomMembers(Vec<Member>),
+    RoomInvited(Vec<Member>),
+    RoomBanned(Vec<Member>),
     RoomBatchEnd(String, String),
     SendMsg,
     DirectoryProtocols(Vec<Protocol>),
     DirectorySearch(Vec<Room>),
     JoinRoom,
     LeaveRoom,
+    Invite,
+    Kick,
+    Ban,
+    Unban,
     MarkedAsRead(String, String),
     SetRoomName,
     SetRoomTopic,
     SetRoomAvatar,
+    SetPowerLevels,
     RoomName(String, String),
     RoomTopic(String, String),
+    RoomAlias(String, String),
     Media(String),
     AttachedFile(Message),
     SearchEnd,
     NotificationClicked(Message),
+    EditClicked(Message),
+    ReplyClicked(Message),
+    QuoteClicked(String, String),
 
     //errors
     UserNameError(Error),
@@ -131,6 +196,12 @@ pub enum BKResponse {
     DirectoryError(Error),
     JoinRoomError(Error),
     MarkAsReadError(Error),
+    SendTypingError(Error),
+    FullyReadError(Error),
+    GetFullyReadError(String, Error),
+    SetPresenceError(Error),
+    RedactError(Error),
+    SendReactionError(Error),
     LeaveRoomError(Error),
     SetRoomNameError(Error),
     SetRoomTopicError(Error),
@@ -139,6 +210,17 @@ pub enum BKResponse {
     MediaError(Error),
     AttachFileError(Error),
     SearchError(Error),
+    SetUserNameError(Error),
+    InviteError(Error),
+    KickError(Error),
+    BanError(Error),
+    UnbanError(Error),
+    DirectChatError(Error),
+    DirectRoomsError(Error),
+    CreateRoomError(Error),
+    UserSearchError(Error),
+    RoomGapFilledError(String, String),
+    SetPowerLevelsError(Error),
 }
 
 
@@ -149,11 +231,14 @@ impl Backend {
             access_token: String::from(""),
             server_url: String::from("https://matrix.org"),
             since: String::from(""),
+            filter_id: String::from(""),
             msgid: 1,
             msgs_batch_start: String::from(""),
             msgs_batch_end: String::from(""),
             rooms_since: String::from(""),
             join_to_room: String::from(""),
+            presence: String::from("online"),
+            direct: HashMap::new(),
         };
         Backend {
             tx: tx,
@@ -212,6 +297,22 @@ impl Backend {
                 let r = self.sync();
                 bkerror!(r, tx, BKResponse::SyncError);
             }
+            Ok(BKCommand::SetSince(since)) => {
+                self.data.lock().unwrap().since = since;
+            }
+            Ok(BKCommand::SetDirect(direct)) => {
+                self.data.lock().unwrap().direct = direct;
+            }
+            Ok(BKCommand::GetDirect) => {
+                let r = self.get_direct();
+                bkerror!(r, tx, BKResponse::DirectRoomsError);
+            }
+            Ok(BKCommand::SetFilter(filter, def)) => {
+                // the stored filter is only valid if it's the same that we build now
+                if def == self.sync_filter().to_string() {
+                    self.data.lock().unwrap().filter_id = filter;
+                }
+            }
             Ok(BKCommand::GetRoomMessages(room)) => {
                 let r = self.get_room_messages(room, false);
                 bkerror!(r, tx, BKResponse::RoomMessagesError);
@@ -220,6 +321,12 @@ impl Backend {
                 let r = self.get_room_messages(room, true);
                 bkerror!(r, tx, BKResponse::RoomMessagesError);
             }
+            Ok(BKCommand::FillGap(room, msgid, from, until)) => {
+                let r = self.fill_gap(room.clone(), msgid.clone(), from, until);
+                if let Err(_) = r {
+                    tx.send(BKResponse::RoomGapFilledError(room, msgid)).unwrap();
+                }
+            }
             Ok(BKCommand::GetUserInfoAsync(sender, ctx)) => {
                 let r = self.get_user_info_async(&sender, ctx);
                 bkerror!(r, tx, BKResponse::CommandError);
@@ -270,10 +377,44 @@ impl Backend {
                 let r = self.leave_room(roomid);
                 bkerror!(r, tx, BKResponse::LeaveRoomError);
             }
+            Ok(BKCommand::AcceptInv(roomid, direct)) => {
+                let r = self.accept_inv(roomid, direct);
+                bkerror!(r, tx, BKResponse::JoinRoomError);
+            }
+            Ok(BKCommand::RejectInv(roomid)) => {
+                let r = self.reject_inv(roomid);
+                bkerror!(r, tx, BKResponse::LeaveRoomError);
+            }
             Ok(BKCommand::MarkAsRead(roomid, evid)) => {
                 let r = self.mark_as_read(roomid, evid);
                 bkerror!(r, tx, BKResponse::MarkAsReadError);
             }
+            Ok(BKCommand::SendTyping(roomid, typing)) => {
+                let r = self.send_typing(roomid, typing);
+                bkerror!(r, tx, BKResponse::SendTypingError);
+            }
+            Ok(BKCommand::GetFullyRead(roomid)) => {
+                let r = self.get_fully_read(roomid.clone());
+                if let Err(e) = r {
+                    tx.send(BKResponse::GetFullyReadError(roomid, e)).unwrap();
+                }
+            }
+            Ok(BKCommand::SetPresence(presence, status)) => {
+                let r = self.set_presence(presence, status);
+                bkerror!(r, tx, BKResponse::SetPresenceError);
+            }
+            Ok(BKCommand::Redact(roomid, evid, reason)) => {
+                let r = self.redact(roomid, evid, reason);
+                bkerror!(r, tx, BKResponse::RedactError);
+            }
+            Ok(BKCommand::SendReaction(roomid, evid, key)) => {
+                let r = self.send_reaction(roomid, evid, key);
+                bkerror!(r, tx, BKResponse::SendReactionError);
+            }
+            Ok(BKCommand::SetFullyRead(roomid, evid)) => {
+                let r = self.set_fully_read(roomid, evid);
+                bkerror!(r, tx, BKResponse::FullyReadError);
+            }
             Ok(BKCommand::SetRoomName(roomid, name)) => {
                 let r = self.set_room_name(roomid, name);
                 bkerror!(r, tx, BKResponse::SetRoomNameError);
@@ -301,6 +442,55 @@ impl Backend {
             Ok(BKCommand::NotifyClicked(message)) => {
                 tx.send(BKResponse::NotificationClicked(message)).unwrap();
             }
+            Ok(BKCommand::EditClicked(message)) => {
+                tx.send(BKResponse::EditClicked(message)).unwrap();
+            }
+            Ok(BKCommand::ReplyClicked(message)) => {
+                tx.send(BKResponse::ReplyClicked(message)).unwrap();
+            }
+            Ok(BKCommand::QuoteClicked(roomid, evid)) => {
+                tx.send(BKResponse::QuoteClicked(roomid, evid)).unwrap();
+            }
+            Ok(BKCommand::GetMessageAsync(roomid, evid, ctx)) => {
+                let r = self.get_message_async(roomid, evid, ctx);
+                bkerror!(r, tx, BKResponse::CommandError);
+            }
+            Ok(BKCommand::SetUserName(name)) => {
+                let r = self.set_username(name);
+                bkerror!(r, tx, BKResponse::SetUserNameError);
+            }
+            Ok(BKCommand::Invite(roomid, userid)) => {
+                let r = self.invite(roomid, userid);
+                bkerror!(r, tx, BKResponse::InviteError);
+            }
+            Ok(BKCommand::Kick(roomid, userid, reason)) => {
+                let r = self.kick(roomid, userid, reason);
+                bkerror!(r, tx, BKResponse::KickError);
+            }
+            Ok(BKCommand::Ban(roomid, userid, reason)) => {
+                let r = self.ban(roomid, userid, reason);
+                bkerror!(r, tx, BKResponse::BanError);
+            }
+            Ok(BKCommand::Unban(roomid, userid, reason)) => {
+                let r = self.unban(roomid, userid, reason);
+                bkerror!(r, tx, BKResponse::UnbanError);
+            }
+            Ok(BKCommand::DirectChat(userid)) => {
+                let r = self.direct_chat(userid);
+                bkerror!(r, tx, BKResponse::DirectChatError);
+            }
+            Ok(BKCommand::CreateRoom(room)) => {
+                let r = self.create_room(room);
+                bkerror!(r, tx, BKResponse::CreateRoomError);
+            }
+            Ok(BKCommand::UserSearch(term)) => {
+                let r = self.user_search(term);
+                bkerror!(r, tx, BKResponse::UserSearchError);
+            }
+            Ok(BKCommand::SetPowerLevels(roomid, changes)) => {
+                let r = self.set_power_levels(roomid, changes);
+                bkerror!(r, tx, BKResponse::SetPowerLevelsError);
+            }
             Ok(BKCommand::ShutDown) => {
                 return false;
             }
@@ -352,6 +542,7 @@ impl Backend {
             data.lock().unwrap().user_id = uid.clone();
             data.lock().unwrap().access_token = tk.clone();
             data.lock().unwrap().since = String::from("");
+            data.lock().unwrap().filter_id = String::from("");
             tx.send(BKResponse::Token(uid, tk)).unwrap();
             tx.send(BKResponse::Rooms(vec![], None)).unwrap();
         },
@@ -385,6 +576,7 @@ impl Backend {
                     data.lock().unwrap().access_token = tk.clone();
                     data.lock().unwrap().msgs_batch_start = String::from("");
                     data.lock().unwrap().since = String::from("");
+                    data.lock().unwrap().filter_id = String::from("");
                     tx.send(BKResponse::Token(uid, tk)).unwrap();
                 }
             },
@@ -417,6 +609,7 @@ impl Backend {
                 data.lock().unwrap().user_id = uid.clone();
                 data.lock().unwrap().access_token = tk.clone();
                 data.lock().unwrap().since = String::from("");
+                data.lock().unwrap().filter_id = String::from("");
                 tx.send(BKResponse::Token(uid, tk)).unwrap();
             },
             |err| { tx.send(BKResponse::LoginError(err)).unwrap() }
@@ -440,6 +633,23 @@ impl Backend {
         Ok(())
     }
 
+    pub fn set_username(&self, name: String) -> Result<(), Error> {
+        let id = self.data.lock().unwrap().user_id.clone();
+        let url = self.url(&format!("profile/{}/displayname", id), vec![])?;
+
+        let attrs = json!({
+            "displayname": name,
+        });
+
+        let tx = self.tx.clone();
+        query!("put", &url, &attrs,
+            |_| { tx.send(BKResponse::Name(name)).unwrap(); },
+            |err| { tx.send(BKResponse::SetUserNameError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
     pub fn get_avatar(&self) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let userid = self.data.lock().unwrap().user_id.clone();
@@ -465,6 +675,8 @@ impl Backend {
 
         let since = self.data.lock().unwrap().since.clone();
         let userid = self.data.lock().unwrap().user_id.clone();
+        let filter_id = self.data.lock().unwrap().filter_id.clone();
+        let presence = self.data.lock().unwrap().presence.clone();
 
         let mut params: Vec<(&str, String)> = vec![];
         let timeout = 120;
@@ -472,39 +684,55 @@ impl Backend {
         params.push(("full_state", strn!("false")));
         params.push(("timeout", strn!("30000")));
 
-        if since.is_empty() {
-            let filter = "{ \
-                \"room\": { \
-                    \"state\": { \
-                        \"types\": [\"m.room.*\"], \
-                    }, \
-                    \"timeline\": { \
-                        \"types\": [\"m.room.message\"], \
-                        \"limit\": 10, \
-                    }, \
-                    \"ephemeral\": { \"types\": [] } \
-                }, \
-                \"presence\": { \"types\": [] }, \
-                \"event_format\": \"client\", \
-                \"event_fields\": [\"type\", \"content\", \"sender\", \"event_id\", \"age\", \"unsigned\"] \
-            }";
-
-            params.push(("filter", strn!(filter)));
-        } else {
+        // the server sets us online with each sync if we don't tell it
+        if presence != "online" {
+            params.push(("set_presence", presence));
+        }
+
+        if !since.is_empty() {
             params.push(("since", since.clone()));
         }
 
         let baseu = self.get_base_url()?;
-        let url = self.url("sync", params)?;
+        let mut url = self.url("sync", params)?;
+
+        let filter = self.sync_filter();
+        let filter_url = self.url(&format!("user/{}/filter", userid), vec![])?;
 
         let tx = self.tx.clone();
         let data = self.data.clone();
 
         let attrs = json!(null);
         thread::spawn(move || {
+            // the filter is uploaded only once, and then we use the filter id
+            let f = match filter_id {
+                ref id if !id.is_empty() => id.clone(),
+                _ => match json_q("post", &filter_url, &filter, timeout!()) {
+                    Ok(js) => {
+                        let id = strn!(js["filter_id"].as_str().unwrap_or(""));
+                        data.lock().unwrap().filter_id = id.clone();
+                        tx.send(BKResponse::SyncFilter(id.clone(), filter.to_string())).unwrap();
+                        id
+                    }
+                    // we can also send the filter definition in the sync request
+                    Err(_) => filter.to_string(),
+                },
+            };
+            url.query_pairs_mut().append_pair("filter", &f);
+
             match json_q("get", &url, &attrs, timeout) {
                 Ok(r) => {
                     let next_batch = String::from(r["next_batch"].as_str().unwrap_or(""));
+
+                    for (uid, presence, status) in get_presence_from_json(&r) {
+                        tx.send(BKResponse::UserPresence(uid, presence, status)).unwrap();
+                    }
+
+                    if let Some(direct) = get_direct_from_json(&r) {
+                        data.lock().unwrap().direct = direct.clone();
+                        tx.send(BKResponse::DirectRooms(direct)).unwrap();
+                    }
+
                     if since.is_empty() {
                         let rooms = match get_rooms_from_json(r, &userid, &baseu) {
                             Ok(rs) => rs,
@@ -523,11 +751,58 @@ impl Backend {
                         }
                         tx.send(BKResponse::Rooms(rooms, def)).unwrap();
                     } else {
+                        // New rooms
+                        match get_new_rooms_from_json(&r, &userid) {
+                            Ok(rs) => if !rs.is_empty() {
+                                tx.send(BKResponse::NewRooms(rs)).unwrap();
+                            },
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        };
+                        // Left rooms
+                        match get_left_rooms_from_json(&r, &userid) {
+                            Ok(rs) => for (roomid, ev) in rs {
+                                tx.send(BKResponse::LeftRoom(roomid, ev)).unwrap();
+                            },
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        };
                         // Message events
                         match get_rooms_timeline_from_json(&baseu, &r) {
-                            Ok(msgs) => tx.send(BKResponse::RoomMessages(msgs)).unwrap(),
+                            Ok((msgs, gaps)) => {
+                                tx.send(BKResponse::RoomMessages(msgs)).unwrap();
+                                for (roomid, token) in gaps {
+                                    tx.send(BKResponse::RoomTimelineGap(roomid, token)).unwrap();
+                                }
+                            }
                             Err(err) => tx.send(BKResponse::RoomMessagesError(err)).unwrap(),
                         };
+                        // Unread notifications
+                        match get_rooms_notifies_from_json(&r) {
+                            Ok(notifies) => for (roomid, n, h) in notifies {
+                                tx.send(BKResponse::RoomNotifications(roomid, n, h)).unwrap();
+                            },
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        };
+                        // Typing users
+                        match get_typing_from_json(&r) {
+                            Ok(typing) => for (roomid, users) in typing {
+                                tx.send(BKResponse::RoomTyping(roomid, users)).unwrap();
+                            },
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        };
+                        // Read receipts
+                        match get_receipts_from_json(&r) {
+                            Ok(receipts) => for (roomid, rs) in receipts {
+                                tx.send(BKResponse::RoomReceipts(roomid, rs)).unwrap();
+                            },
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        };
+                        // Fully read markers
+                        match get_fully_read_from_json(&r) {
+                            Ok(markers) => for (roomid, evid) in markers {
+                                tx.send(BKResponse::RoomFullyRead(roomid, evid)).unwrap();
+                            },
+                            Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
+                        };
                         // Other events
                         match parse_sync_events(&r) {
                             Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
@@ -545,9 +820,25 @@ impl Backend {
                                         "m.room.avatar" => {
                                             tx.send(BKResponse::NewRoomAvatar(ev.room.clone())).unwrap();
                                         }
+                                        "m.room.canonical_alias" => {
+                                            let a = strn!(ev.content["alias"].as_str().unwrap_or(""));
+                                            tx.send(BKResponse::RoomAlias(ev.room.clone(), a)).unwrap();
+                                        }
                                         "m.room.member" => {
                                             tx.send(BKResponse::RoomMemberEvent(ev)).unwrap();
                                         }
+                                        "m.room.power_levels" => {
+                                            let pl = parse_power_levels(&ev.content);
+                                            tx.send(BKResponse::RoomPowerLevels(ev.room.clone(), pl)).unwrap();
+                                        }
+                                        "m.reaction" => {
+                                            if let Some(r) = parse_reaction(&ev.id, &ev.sender, &ev.content) {
+                                                tx.send(BKResponse::RoomReaction(ev.room.clone(), r)).unwrap();
+                                            }
+                                        }
+                                        "m.room.redaction" => {
+                                            tx.send(BKResponse::RoomRedaction(ev.room.clone(), ev.redacts.clone())).unwrap();
+                                        }
                                         _ => {
                                             println!("EVENT NOT MANAGED: {:?}", ev);
                                         }
@@ -557,17 +848,37 @@ impl Backend {
                         };
                     }
 
-                    data.lock().unwrap().since = next_batch;
+                    data.lock().unwrap().since = next_batch.clone();
 
-                    tx.send(BKResponse::Sync).unwrap();
+                    tx.send(BKResponse::Sync(next_batch)).unwrap();
                 },
-                Err(err) => { tx.send(BKResponse::SyncError(err)).unwrap() }
+                Err(err) => {
+                    // the server can forget the filter, so if it doesn't
+                    // like the id we upload the filter again in the next sync
+                    if let Error::MatrixError(_) = err {
+                        if !filter_id.is_empty() {
+                            data.lock().unwrap().filter_id = String::from("");
+                        }
+                    }
+                    tx.send(BKResponse::SyncError(err)).unwrap()
+                }
             };
         });
 
         Ok(())
     }
 
+    pub fn sync_filter(&self) -> JsonValue {
+        SyncFilter::new()
+            .timeline_limit(10)
+            .typing(true)
+            .receipts(true)
+            .presence(true)
+            .event_fields(vec!["type", "content", "sender", "state_key",
+                               "event_id", "age", "unsigned", "redacts"])
+            .to_json()
+    }
+
     pub fn get_room_detail(&self, roomid: String, key: String) -> Result<(), Error> {
         let url = self.url(&format!("rooms/{}/state/{}", roomid, key), vec![])?;
 
@@ -642,7 +953,7 @@ impl Backend {
                 false => None,
             };
             match get_initial_room_messages(&baseu, tk, roomid.clone(), 10 as usize, 10, end) {
-                Ok((ms, start, end)) => {
+                Ok((ms, reactions, start, end)) => {
                     data.lock().unwrap().msgs_batch_start = start;
                     data.lock().unwrap().msgs_batch_end = end.clone();
 
@@ -652,6 +963,10 @@ impl Backend {
                         false => tx.send(BKResponse::RoomMessagesInit(ms)).unwrap(),
                         true => tx.send(BKResponse::RoomMessagesTo(ms)).unwrap(),
                     };
+
+                    for r in reactions {
+                        tx.send(BKResponse::RoomReaction(roomid.clone(), r)).unwrap();
+                    }
                 }
                 Err(err) => {
                     tx.send(BKResponse::RoomMessagesError(err)).unwrap();
@@ -662,6 +977,43 @@ impl Backend {
         Ok(())
     }
 
+    /// Back-fills the missing messages before the @msgid message, from the
+    /// @from token until the @until event.
+    pub fn fill_gap(&self, roomid: String, msgid: String, from: String, until: String) -> Result<(), Error> {
+        let baseu = self.get_base_url()?;
+        let tk = self.data.lock().unwrap().access_token.clone();
+
+        let tx = self.tx.clone();
+        thread::spawn(move || {
+            match get_gap_messages(&baseu, tk, roomid.clone(), from, until, 5) {
+                Ok((ms, evs, token)) => {
+                    tx.send(BKResponse::RoomGapFilled(roomid.clone(), msgid, ms, token)).unwrap();
+
+                    // the messages in the gap are already redacted, but
+                    // not the ones that we had before
+                    for ev in evs.iter() {
+                        match ev.stype.as_ref() {
+                            "m.room.redaction" => {
+                                tx.send(BKResponse::RoomRedaction(roomid.clone(), ev.redacts.clone())).unwrap();
+                            }
+                            "m.reaction" => {
+                                if let Some(r) = parse_reaction(&ev.id, &ev.sender, &ev.content) {
+                                    tx.send(BKResponse::RoomReaction(roomid.clone(), r)).unwrap();
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Err(_) => {
+                    tx.send(BKResponse::RoomGapFilledError(roomid, msgid)).unwrap();
+                }
+            }
+        });
+
+        Ok(())
+    }
+
     pub fn get_room_members(&self, roomid: String) -> Result<(), Error> {
         let url = self.url(&format!("rooms/{}/members", roomid), vec![])?;
 
@@ -670,24 +1022,33 @@ impl Backend {
             |r: JsonValue| {
                 //println!("{:#?}", r);
                 let mut ms: Vec<Member> = vec![];
+                let mut invited: Vec<Member> = vec![];
+                let mut banned: Vec<Member> = vec![];
                 for member in r["chunk"].as_array().unwrap().iter().rev() {
                     if member["type"].as_str().unwrap() != "m.room.member" {
                         continue;
                     }
 
+                    // the member is the state_key, the sender of the
+                    // invites is the user that has invited
                     let content = &member["content"];
-                    if content["membership"].as_str().unwrap() != "join" {
-                        continue;
-                    }
-
+                    let sender = member["sender"].as_str().unwrap_or("");
                     let m = Member {
                         alias: String::from(content["displayname"].as_str().unwrap_or("")),
-                        uid: String::from(member["sender"].as_str().unwrap()),
+                        uid: String::from(member["state_key"].as_str().unwrap_or(sender)),
                         avatar: String::from(content["avatar_url"].as_str().unwrap_or("")),
                     };
-                    ms.push(m);
+
+                    match content["membership"].as_str() {
+                        Some("join") => ms.push(m),
+                        Some("invite") => invited.push(m),
+                        Some("ban") => banned.push(m),
+                        _ => {}
+                    }
                 }
                 tx.send(BKResponse::RoomMembers(ms)).unwrap();
+                tx.send(BKResponse::RoomInvited(invited)).unwrap();
+                tx.send(BKResponse::RoomBanned(banned)).unwrap();
             },
             |err| { tx.send(BKResponse::RoomMembersError(err)).unwrap() }
         );
@@ -735,6 +1096,21 @@ impl Backend {
         Ok(())
     }
 
+    pub fn get_message_async(&self, roomid: String, evid: String, tx: Sender<Message>) -> Result<(), Error> {
+        let baseu = self.get_base_url()?;
+        let url = self.url(&format!("rooms/{}/event/{}", roomid, evid), vec![])?;
+
+        let etx = self.tx.clone();
+        get!(&url,
+            |r: JsonValue| {
+                tx.send(parse_room_message(&baseu, roomid, &r)).unwrap();
+            },
+            |err| { etx.send(BKResponse::CommandError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
     pub fn get_thumb_async(&self, media: String, tx: Sender<String>) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
 
@@ -782,12 +1158,45 @@ impl Backend {
 
         let url = self.url(&format!("rooms/{}/send/m.room.message/{}", roomid, msgid), vec![])?;
 
-        let attrs = json!({
+        let mut attrs = json!({
             "body": msg.body.clone(),
             "url": msg.url.clone(),
             "msgtype": msg.mtype.clone()
         });
 
+        if let Some(ref formatted) = msg.formatted {
+            attrs["format"] = json!("org.matrix.custom.html");
+            attrs["formatted_body"] = json!(formatted);
+        }
+
+        if let Some(ref evid) = msg.in_reply_to {
+            attrs["m.relates_to"] = json!({
+                "m.in_reply_to": { "event_id": evid }
+            });
+        }
+
+        if let Some(ref evid) = msg.replaces {
+            attrs = json!({
+                "body": format!("* {}", msg.body),
+                "msgtype": msg.mtype.clone(),
+                "m.new_content": {
+                    "body": msg.body.clone(),
+                    "msgtype": msg.mtype.clone(),
+                },
+                "m.relates_to": {
+                    "rel_type": "m.replace",
+                    "event_id": evid,
+                },
+            });
+
+            if let Some(ref formatted) = msg.formatted {
+                attrs["format"] = json!("org.matrix.custom.html");
+                attrs["formatted_body"] = json!(format!("* {}", formatted));
+                attrs["m.new_content"]["format"] = json!("org.matrix.custom.html");
+                attrs["m.new_content"]["formatted_body"] = json!(formatted);
+            }
+        }
+
         let tx = self.tx.clone();
         query!("put", &url, &attrs,
             move |_| {
@@ -893,13 +1302,16 @@ impl Backend {
     }
 
     pub fn join_room(&self, roomid: String) -> Result<(), Error> {
-        let url = self.url(&format!("rooms/{}/join", roomid), vec![])?;
+        // the room can be an alias, and the '#' can't be in the url path
+        let id = utf8_percent_encode(&roomid, PATH_SEGMENT_ENCODE_SET).to_string();
+        let url = self.url(&format!("join/{}", id), vec![])?;
 
         let tx = self.tx.clone();
         let data = self.data.clone();
         post!(&url,
-            move |_: JsonValue| {
-                data.lock().unwrap().join_to_room = roomid.clone();
+            move |r: JsonValue| {
+                let id = String::from(r["room_id"].as_str().unwrap_or(&roomid));
+                data.lock().unwrap().join_to_room = id;
                 tx.send(BKResponse::JoinRoom).unwrap();
             },
             |err| { tx.send(BKResponse::JoinRoomError(err)).unwrap(); }
@@ -922,6 +1334,246 @@ impl Backend {
         Ok(())
     }
 
+    pub fn user_search(&self, term: String) -> Result<(), Error> {
+        let url = self.url("user_directory/search", vec![])?;
+
+        let attrs = json!({
+            "search_term": term,
+            "limit": 20,
+        });
+
+        let tx = self.tx.clone();
+        let t = term.clone();
+        post!(&url, &attrs,
+            move |r: JsonValue| {
+                let empty = vec![];
+                let users = r["results"].as_array().unwrap_or(&empty).iter().map(|u| {
+                    Member {
+                        alias: String::from(u["display_name"].as_str().unwrap_or("")),
+                        uid: String::from(u["user_id"].as_str().unwrap_or("")),
+                        avatar: String::from(u["avatar_url"].as_str().unwrap_or("")),
+                    }
+                }).collect();
+                tx.send(BKResponse::UserSearch(t, users)).unwrap();
+            },
+            |_| {
+                // the raw user ids can be invited without the user directory
+                tx.send(BKResponse::UserSearch(term, vec![])).unwrap();
+            }
+        );
+
+        Ok(())
+    }
+
+    pub fn invite(&self, roomid: String, userid: String) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/invite", roomid), vec![])?;
+
+        let attrs = json!({
+            "user_id": userid,
+        });
+
+        let tx = self.tx.clone();
+        post!(&url, &attrs,
+            |_| { tx.send(BKResponse::Invite).unwrap(); },
+            |err| { tx.send(BKResponse::InviteError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn kick(&self, roomid: String, userid: String, reason: String) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/kick", roomid), vec![])?;
+
+        let mut attrs = json!({
+            "user_id": userid,
+        });
+        if !reason.is_empty() {
+            attrs["reason"] = json!(reason);
+        }
+
+        let tx = self.tx.clone();
+        post!(&url, &attrs,
+            |_| { tx.send(BKResponse::Kick).unwrap(); },
+            |err| { tx.send(BKResponse::KickError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn ban(&self, roomid: String, userid: String, reason: String) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/ban", roomid), vec![])?;
+
+        let mut attrs = json!({
+            "user_id": userid,
+        });
+        if !reason.is_empty() {
+            attrs["reason"] = json!(reason);
+        }
+
+        let tx = self.tx.clone();
+        post!(&url, &attrs,
+            |_| { tx.send(BKResponse::Ban).unwrap(); },
+            |err| { tx.send(BKResponse::BanError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn unban(&self, roomid: String, userid: String, reason: String) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/unban", roomid), vec![])?;
+
+        let mut attrs = json!({
+            "user_id": userid,
+        });
+        if !reason.is_empty() {
+            attrs["reason"] = json!(reason);
+        }
+
+        let tx = self.tx.clone();
+        post!(&url, &attrs,
+            |_| { tx.send(BKResponse::Unban).unwrap(); },
+            |err| { tx.send(BKResponse::UnbanError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    /// Creates a new room, the empty fields aren't sent. The room is
+    /// selected after the sync, like the joined rooms.
+    pub fn create_room(&self, room: NewRoom) -> Result<(), Error> {
+        let url = self.url("createRoom", vec![])?;
+
+        let mut attrs = json!({
+            "visibility": if room.public { "public" } else { "private" },
+            "preset": room.preset,
+            "invite": room.invites,
+        });
+        if !room.name.is_empty() {
+            attrs["name"] = json!(room.name);
+        }
+        if !room.topic.is_empty() {
+            attrs["topic"] = json!(room.topic);
+        }
+        if !room.alias.is_empty() {
+            attrs["room_alias_name"] = json!(room.alias);
+        }
+
+        let tx = self.tx.clone();
+        let data = self.data.clone();
+        post!(&url, &attrs,
+            move |r: JsonValue| {
+                let id = String::from(r["room_id"].as_str().unwrap_or(""));
+                data.lock().unwrap().join_to_room = id;
+                tx.send(BKResponse::JoinRoom).unwrap();
+            },
+            |err| { tx.send(BKResponse::CreateRoomError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    /// Creates a private room with the user, and selects it after the sync.
+    /// The room is added to the m.direct account data.
+    pub fn direct_chat(&self, userid: String) -> Result<(), Error> {
+        let url = self.url("createRoom", vec![])?;
+        let me = self.data.lock().unwrap().user_id.clone();
+        let directurl = self.url(&format!("user/{}/account_data/m.direct", me), vec![])?;
+
+        let attrs = json!({
+            "invite": [userid],
+            "is_direct": true,
+            "preset": "trusted_private_chat",
+            "visibility": "private",
+        });
+
+        let tx = self.tx.clone();
+        let data = self.data.clone();
+        post!(&url, &attrs,
+            move |r: JsonValue| {
+                let id = String::from(r["room_id"].as_str().unwrap_or(""));
+                data.lock().unwrap().join_to_room = id.clone();
+
+                tx.send(BKResponse::JoinRoom).unwrap();
+
+                match add_direct_room(&directurl, &userid, &id) {
+                    Ok(direct) => {
+                        data.lock().unwrap().direct = direct.clone();
+                        tx.send(BKResponse::DirectRooms(direct)).unwrap();
+                    }
+                    Err(err) => { tx.send(BKResponse::DirectChatError(err)).unwrap(); }
+                }
+            },
+            |err| { tx.send(BKResponse::DirectChatError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    /// Asks for the m.direct account data, the direct chats with each user
+    pub fn get_direct(&self) -> Result<(), Error> {
+        let me = self.data.lock().unwrap().user_id.clone();
+        let url = self.url(&format!("user/{}/account_data/m.direct", me), vec![])?;
+
+        let tx = self.tx.clone();
+        let data = self.data.clone();
+        get!(&url,
+            move |r: JsonValue| {
+                let direct = get_direct_from_content(&r);
+                data.lock().unwrap().direct = direct.clone();
+                tx.send(BKResponse::DirectRooms(direct)).unwrap();
+            },
+            |err| {
+                match err {
+                    // we haven't any direct chat yet
+                    Error::MatrixError(ref js) if js["errcode"].as_str().unwrap_or("") == "M_NOT_FOUND" => {
+                        tx.send(BKResponse::DirectRooms(HashMap::new())).unwrap();
+                    }
+                    err => { tx.send(BKResponse::DirectRoomsError(err)).unwrap(); }
+                }
+            }
+        );
+
+        Ok(())
+    }
+
+    /// Accepting an invitation is just joining the room. The direct chats
+    /// are added to the m.direct with the user that has invited us.
+    pub fn accept_inv(&self, roomid: String, direct: Option<String>) -> Result<(), Error> {
+        let userid = match direct {
+            Some(u) => u,
+            None => { return self.join_room(roomid); }
+        };
+
+        let url = self.url(&format!("join/{}", roomid), vec![])?;
+        let me = self.data.lock().unwrap().user_id.clone();
+        let directurl = self.url(&format!("user/{}/account_data/m.direct", me), vec![])?;
+
+        let tx = self.tx.clone();
+        let data = self.data.clone();
+        post!(&url,
+            move |_: JsonValue| {
+                data.lock().unwrap().join_to_room = roomid.clone();
+                tx.send(BKResponse::JoinRoom).unwrap();
+
+                match add_direct_room(&directurl, &userid, &roomid) {
+                    Ok(direct) => {
+                        data.lock().unwrap().direct = direct.clone();
+                        tx.send(BKResponse::DirectRooms(direct)).unwrap();
+                    }
+                    Err(err) => { tx.send(BKResponse::DirectRoomsError(err)).unwrap(); }
+                }
+            },
+            |err| { tx.send(BKResponse::JoinRoomError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn reject_inv(&self, roomid: String) -> Result<(), Error> {
+        // and rejecting is leaving it
+        self.leave_room(roomid)
+    }
+
     pub fn mark_as_read(&self, roomid: String, eventid: String) -> Result<(), Error> {
         let url = self.url(&format!("rooms/{}/receipt/m.read/{}", roomid, eventid), vec![])?;
 
@@ -936,6 +1588,143 @@ impl Backend {
         Ok(())
     }
 
+    pub fn send_typing(&self, roomid: String, typing: bool) -> Result<(), Error> {
+        let userid = self.data.lock().unwrap().user_id.clone();
+        let url = self.url(&format!("rooms/{}/typing/{}", roomid, userid), vec![])?;
+
+        // the server stops the typing notification after the timeout, so
+        // we don't need to worry if the stop request never arrives
+        let attrs = if typing {
+            json!({ "typing": true, "timeout": 5000 })
+        } else {
+            json!({ "typing": false })
+        };
+
+        let tx = self.tx.clone();
+        query!("put", &url, &attrs,
+            |_| { },
+            |err| { tx.send(BKResponse::SendTypingError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn redact(&self, roomid: String, eventid: String, reason: String) -> Result<(), Error> {
+        let msgid;
+        {
+            let mut data = self.data.lock().unwrap();
+            data.msgid = data.msgid + 1;
+            msgid = data.msgid;
+        }
+
+        let url = self.url(&format!("rooms/{}/redact/{}/{}", roomid, eventid, msgid), vec![])?;
+
+        let attrs = match reason {
+            ref r if r.is_empty() => json!({}),
+            r => json!({ "reason": r }),
+        };
+
+        let tx = self.tx.clone();
+        query!("put", &url, &attrs,
+            move |_| { tx.send(BKResponse::RoomRedaction(roomid, eventid)).unwrap(); },
+            |err| { tx.send(BKResponse::RedactError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn send_reaction(&self, roomid: String, evid: String, key: String) -> Result<(), Error> {
+        let userid = self.data.lock().unwrap().user_id.clone();
+        let msgid;
+        {
+            let mut data = self.data.lock().unwrap();
+            data.msgid = data.msgid + 1;
+            msgid = data.msgid;
+        }
+
+        let url = self.url(&format!("rooms/{}/send/m.reaction/{}", roomid, msgid), vec![])?;
+
+        let attrs = json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": evid,
+                "key": key,
+            }
+        });
+
+        let tx = self.tx.clone();
+        query!("put", &url, &attrs,
+            move |r: JsonValue| {
+                // the reaction is shown before the sync echo
+                let reaction = Reaction {
+                    id: strn!(r["event_id"].as_str().unwrap_or("")),
+                    sender: userid,
+                    target: evid,
+                    key: key,
+                };
+                tx.send(BKResponse::RoomReaction(roomid, reaction)).unwrap();
+            },
+            |err| { tx.send(BKResponse::SendReactionError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn set_presence(&self, presence: String, status: String) -> Result<(), Error> {
+        let userid = self.data.lock().unwrap().user_id.clone();
+        let url = self.url(&format!("presence/{}/status", userid), vec![])?;
+
+        self.data.lock().unwrap().presence = presence.clone();
+
+        let attrs = json!({
+            "presence": presence,
+            "status_msg": status,
+        });
+
+        let tx = self.tx.clone();
+        query!("put", &url, &attrs,
+            |_| { },
+            |err| { tx.send(BKResponse::SetPresenceError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn get_fully_read(&self, roomid: String) -> Result<(), Error> {
+        let userid = self.data.lock().unwrap().user_id.clone();
+        let url = self.url(&format!("user/{}/rooms/{}/account_data/m.fully_read",
+                                    userid, roomid), vec![])?;
+
+        let tx = self.tx.clone();
+        get!(&url,
+            |r: JsonValue| {
+                let evid = strn!(r["event_id"].as_str().unwrap_or(""));
+                tx.send(BKResponse::RoomFullyRead(roomid, evid)).unwrap();
+            },
+            |err| { tx.send(BKResponse::GetFullyReadError(roomid, err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
+    pub fn set_fully_read(&self, roomid: String, eventid: String) -> Result<(), Error> {
+        // the m.fully_read account data can't be set directly, it's
+        // changed with the read markers API
+        let url = self.url(&format!("rooms/{}/read_markers", roomid), vec![])?;
+
+        let attrs = json!({
+            "m.fully_read": eventid,
+        });
+
+        let tx = self.tx.clone();
+        post!(&url, &attrs,
+            |_| { },
+            |err| { tx.send(BKResponse::FullyReadError(err)).unwrap(); }
+        );
+
+        Ok(())
+    }
+
     pub fn set_room_name(&self, roomid: String, name: String) -> Result<(), Error> {
         let url = self.url(&format!("rooms/{}/state/m.room.name", roomid), vec![])?;
 
@@ -968,6 +1757,60 @@ impl Backend {
         Ok(())
     }
 
+    /// Writes the power levels state event. The current content is read
+    /// first and only the changed levels are written over it, so the fields
+    /// that we don't manage and the changes of other admins aren't lost.
+    pub fn set_power_levels(&self, roomid: String, changes: PowerLevelsChanges) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/state/m.room.power_levels", roomid), vec![])?;
+
+        let tx = self.tx.clone();
+        thread::spawn(move || {
+            // without the current content we would lose the fields that we
+            // don't manage, like the notifications levels
+            let mut content = match json_q("get", &url, &json!(null), timeout!()) {
+                Ok(ref js) if js.is_object() => js.clone(),
+                Ok(_) => {
+                    tx.send(BKResponse::SetPowerLevelsError(Error::BackendError)).unwrap();
+                    return;
+                }
+                Err(err) => {
+                    tx.send(BKResponse::SetPowerLevelsError(err)).unwrap();
+                    return;
+                }
+            };
+
+            for (k, v) in changes.levels {
+                content[&k] = json!(v);
+            }
+            for (ev, v) in changes.events {
+                if !content["events"].is_object() {
+                    content["events"] = json!({});
+                }
+                content["events"][&ev] = json!(v);
+            }
+            for (uid, v) in changes.users {
+                if !content["users"].is_object() {
+                    content["users"] = json!({});
+                }
+                content["users"][&uid] = json!(v);
+            }
+
+            match json_q("put", &url, &content, timeout!()) {
+                Ok(_) => {
+                    if let Ok(pl) = serde_json::from_value::<PowerLevels>(content) {
+                        tx.send(BKResponse::RoomPowerLevels(roomid, pl)).unwrap();
+                    }
+                    tx.send(BKResponse::SetPowerLevels).unwrap();
+                }
+                Err(err) => {
+                    tx.send(BKResponse::SetPowerLevelsError(err)).unwrap();
+                }
+            }
+        });
+
+        Ok(())
+    }
+
     pub fn set_room_avatar(&self, roomid: String, avatar: String) -> Result<(), Error> {
         let baseu = self.get_base_url()?;
         let tk = self.data.lock().unwrap().access_token.clone();
@@ -1047,6 +1890,12 @@ impl Backend {
             thumb: String::from(""),
             url: String::from(""),
             id: String::from(""),
+            gap: None,
+            redacted: false,
+            replaces: None,
+            edited: false,
+            formatted: None,
+            in_reply_to: None,
         };
 
         let tx = self.tx.clone();
@@ -48,16 +48,37 @@ impl<T> CacheMap<T> {
 
 #[derive(Serialize, Deserialize)]
 pub struct CacheData {
+    #[serde(default)]
+    pub since: String,
+    // sync filter id and the filter definition, the id is only valid for
+    // the same definition
+    #[serde(default)]
+    pub filter_id: String,
+    #[serde(default)]
+    pub filter: String,
+    // the m.direct account data, we only get it in the sync when it changes
+    #[serde(default)]
+    pub direct: HashMap<String, Vec<String>>,
     pub rooms: RoomList,
     pub username: String,
     pub uid: String,
 }
 
 
-pub fn store(rooms: &RoomList, username: String, uid: String) -> Result<(), Error> {
+pub fn store(rooms: &RoomList,
+             since: String,
+             filter: (String, String),
+             direct: &HashMap<String, Vec<String>>,
+             username: String,
+             uid: String)
+             -> Result<(), Error> {
     let fname = cache_path("rooms.json")?;
 
     let data = CacheData {
+        since: since,
+        filter_id: filter.0,
+        filter: filter.1,
+        direct: direct.clone(),
         rooms: rooms.clone(),
         username: username,
         uid: uid,
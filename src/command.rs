@@ -0,0 +1,193 @@
+use util::is_uid;
+
+/// A command that can be written in the message entry, like "/join #room:server"
+pub struct Command {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub help: &'static str,
+}
+
+pub const COMMANDS: [Command; 11] = [
+    Command { name: "join", args: "<#alias|!roomid>", help: "Joins the room" },
+    Command { name: "leave", args: "", help: "Leaves the current room" },
+    Command { name: "nick", args: "<name>", help: "Changes your display name" },
+    Command { name: "topic", args: "[topic]", help: "Sets the room topic" },
+    Command { name: "invite", args: "<@user:server>", help: "Invites the user to the room" },
+    Command { name: "kick", args: "<@user:server> [reason]", help: "Kicks the user from the room" },
+    Command { name: "ban", args: "<@user:server> [reason]", help: "Bans the user from the room" },
+    Command { name: "me", args: "<action>", help: "Sends an emote" },
+    Command { name: "query", args: "<@user:server>", help: "Opens a direct chat with the user" },
+    Command { name: "markdown", args: "<on|off>", help: "Sends the messages as markdown or plain text" },
+    Command { name: "plain", args: "<message>", help: "Sends the message without markdown" },
+];
+
+/// What the command asks us to do, the arguments are already validated
+#[derive(Debug, Clone)]
+pub enum Action {
+    Send(String),
+    Join(String),
+    Leave,
+    Nick(String),
+    Topic(String),
+    Invite(String),
+    Kick(String, String),
+    Ban(String, String),
+    Me(String),
+    Query(String),
+    Markdown(bool),
+    Plain(String),
+}
+
+/// Parses the message entry text. The text that isn't a command is sent as
+/// a message, a message can start with "/" writing it twice: "//message".
+///
+/// Returns the usage of the command as error if the arguments are wrong.
+pub fn parse(text: &str) -> Result<Action, String> {
+    if !text.starts_with('/') || text.starts_with("//") {
+        let msg = if text.starts_with("//") {
+            &text[1..]
+        } else {
+            text
+        };
+        return Ok(Action::Send(strn!(msg)));
+    }
+
+    let mut parts = text[1..].splitn(2, char::is_whitespace);
+    let name = parts.next().unwrap_or("");
+    let args = parts.next().unwrap_or("").trim();
+
+    let cmd = match COMMANDS.iter().find(|c| c.name == name) {
+        Some(c) => c,
+        None => { return Err(format!("Unknown command /{}, use // to send a message starting with /", name)); }
+    };
+
+    let mut words = args.splitn(2, char::is_whitespace);
+    let first = strn!(words.next().unwrap_or(""));
+    let rest = strn!(words.next().unwrap_or("").trim());
+
+    let action = match cmd.name {
+        "join" if first.starts_with('#') || first.starts_with('!') => Some(Action::Join(first)),
+        "leave" if args.is_empty() => Some(Action::Leave),
+        "nick" if !args.is_empty() => Some(Action::Nick(strn!(args))),
+        "topic" => Some(Action::Topic(strn!(args))),
+        "invite" if is_uid(&first) && rest.is_empty() => Some(Action::Invite(first)),
+        "kick" if is_uid(&first) => Some(Action::Kick(first, rest)),
+        "ban" if is_uid(&first) => Some(Action::Ban(first, rest)),
+        "me" if !args.is_empty() => Some(Action::Me(strn!(args))),
+        "query" if is_uid(&first) && rest.is_empty() => Some(Action::Query(first)),
+        "markdown" if args == "on" => Some(Action::Markdown(true)),
+        "markdown" if args == "off" => Some(Action::Markdown(false)),
+        "plain" if !args.is_empty() => Some(Action::Plain(strn!(args))),
+        _ => None,
+    };
+
+    match action {
+        Some(a) => Ok(a),
+        None => Err(usage(cmd)),
+    }
+}
+
+/// The names of the commands that start with @prefix
+pub fn complete(prefix: &str) -> Vec<&'static str> {
+    COMMANDS.iter()
+        .filter(|c| c.name.starts_with(prefix))
+        .map(|c| c.name)
+        .collect()
+}
+
+/// The longest start that all the @names have
+pub fn common_prefix(names: &[&str]) -> String {
+    let first = match names.first() {
+        Some(n) => n,
+        None => { return String::new(); }
+    };
+
+    let mut prefix = String::new();
+    for (i, c) in first.chars().enumerate() {
+        if !names.iter().all(|n| n.chars().nth(i) == Some(c)) {
+            break;
+        }
+        prefix.push(c);
+    }
+
+    prefix
+}
+
+pub fn usage(cmd: &Command) -> String {
+    let line = format!("/{} {}", cmd.name, cmd.args);
+    format!("Usage: {} — {}", line.trim(), cmd.help)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_sent() {
+        match parse("hello") {
+            Ok(Action::Send(ref m)) if m == "hello" => {}
+            r => panic!("{:?}", r),
+        }
+        match parse("//escaped") {
+            Ok(Action::Send(ref m)) if m == "/escaped" => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn unknown_commands() {
+        match parse("/xyz foo") {
+            Err(ref e) if e.starts_with("Unknown command /xyz") => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn kick_with_reason() {
+        match parse("/kick @u:server.org go away") {
+            Ok(Action::Kick(ref u, ref reason)) if u == "@u:server.org" && reason == "go away" => {}
+            r => panic!("{:?}", r),
+        }
+        match parse("/kick @u:server.org") {
+            Ok(Action::Kick(ref u, ref reason)) if u == "@u:server.org" && reason.is_empty() => {}
+            r => panic!("{:?}", r),
+        }
+        match parse("/kick someone") {
+            Err(ref e) if e.starts_with("Usage: /kick") => {}
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn markdown_arguments() {
+        match parse("/markdown on") {
+            Ok(Action::Markdown(true)) => {}
+            r => panic!("{:?}", r),
+        }
+        match parse("/markdown off") {
+            Ok(Action::Markdown(false)) => {}
+            r => panic!("{:?}", r),
+        }
+        for text in ["/markdown", "/markdown yes", "/markdown on off"].iter() {
+            match parse(text) {
+                Err(ref e) if e.starts_with("Usage: /markdown <on|off>") => {}
+                r => panic!("{}: {:?}", text, r),
+            }
+        }
+    }
+
+    #[test]
+    fn completion() {
+        assert_eq!(complete("jo"), vec!["join"]);
+        assert_eq!(complete("xyz"), Vec::<&str>::new());
+        assert_eq!(complete("").len(), COMMANDS.len());
+    }
+
+    #[test]
+    fn common_prefix_of_names() {
+        assert_eq!(common_prefix(&[]), "");
+        assert_eq!(common_prefix(&["kick"]), "kick");
+        assert_eq!(common_prefix(&["markdown", "me"]), "m");
+        assert_eq!(common_prefix(&["join", "leave"]), "");
+    }
+}
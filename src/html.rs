@@ -0,0 +1,397 @@
+extern crate regex;
+
+use self::regex::Regex;
+
+use util::html_escape;
+
+// The tags that are allowed in the org.matrix.custom.html messages,
+// the other tags are removed but their text is kept
+//
+// https://matrix.org/docs/spec/client_server/r0.3.0.html#m-room-message-msgtypes
+const ALLOWED_TAGS: [&'static str; 37] = [
+    "font", "del", "h1", "h2", "h3", "h4", "h5", "h6", "blockquote", "p", "a",
+    "ul", "ol", "sup", "sub", "li", "b", "i", "u", "strong", "em", "strike",
+    "code", "hr", "br", "div", "table", "thead", "tbody", "tr", "th", "td",
+    "caption", "pre", "span", "img", "s",
+];
+
+// The content of these tags is never shown
+const HIDDEN_TAGS: [&'static str; 3] = ["script", "style", "mx-reply"];
+
+// The messages are converted in each redraw, so the regexes are only
+// compiled once
+thread_local! {
+    static ATTR_RE: Regex = Regex::new(r#"([\w-]+)\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s"'>]+))"#).unwrap();
+    static ENTITY_RE: Regex = Regex::new(r"&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+}
+
+#[derive(Debug)]
+enum Token {
+    Start(String, Vec<(String, String)>),
+    End(String),
+    Text(String),
+}
+
+// Open tag in the output, with the Pango markup that closes it
+struct Open {
+    tag: String,
+    close: String,
+}
+
+/// Converts an org.matrix.custom.html formatted body to Pango markup.
+///
+/// Only the tags allowed by the spec are converted, the other tags are
+/// ignored. Returns None if the html can't be parsed or if it has content
+/// that can't be shown in a label, like images, so the plain body should
+/// be used instead.
+pub fn to_pango(html: &str) -> Option<String> {
+    let tokens = tokenize(html)?;
+
+    let mut out = String::new();
+    let mut stack: Vec<Open> = vec![];
+    // list counters, None for unordered lists
+    let mut lists: Vec<Option<i32>> = vec![];
+    let mut pre = 0;
+    let mut hidden = 0;
+
+    for token in tokens {
+        match token {
+            Token::Text(_) if hidden > 0 => {}
+            Token::Text(t) => {
+                let text = match pre {
+                    0 => collapse_spaces(&t, out.ends_with(' ') || out.ends_with('\n') || out.is_empty()),
+                    _ => t,
+                };
+                out.push_str(&html_escape(&text));
+            }
+            Token::Start(ref tag, _) if HIDDEN_TAGS.contains(&tag.as_str()) => {
+                hidden += 1;
+            }
+            Token::End(ref tag) if HIDDEN_TAGS.contains(&tag.as_str()) => {
+                if hidden > 0 {
+                    hidden -= 1;
+                }
+            }
+            Token::Start(_, _) | Token::End(_) if hidden > 0 => {}
+            Token::Start(tag, attrs) => {
+                if !ALLOWED_TAGS.contains(&tag.as_str()) {
+                    continue;
+                }
+
+                let (open, close) = match tag.as_str() {
+                    "b" | "strong" => (strn!("<b>"), strn!("</b>")),
+                    "i" | "em" => (strn!("<i>"), strn!("</i>")),
+                    "u" => (strn!("<u>"), strn!("</u>")),
+                    "del" | "strike" | "s" => (strn!("<s>"), strn!("</s>")),
+                    "sup" => (strn!("<sup>"), strn!("</sup>")),
+                    "sub" => (strn!("<sub>"), strn!("</sub>")),
+                    "code" => (strn!("<tt>"), strn!("</tt>")),
+                    "caption" => (strn!("<i>"), strn!("</i>\n")),
+                    "th" => (strn!("<b>"), strn!("</b>\t")),
+                    "td" => (String::new(), strn!("\t")),
+                    "tr" => (String::new(), strn!("\n")),
+                    "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                        let size = match tag.as_str() {
+                            "h1" => "xx-large",
+                            "h2" => "x-large",
+                            "h3" => "large",
+                            _ => "medium",
+                        };
+                        (format!("{}<span size=\"{}\" weight=\"bold\">", newline(&out), size),
+                         strn!("</span>\n"))
+                    }
+                    "p" | "div" | "table" => (newline(&out), strn!("\n")),
+                    "blockquote" => (format!("{}<span foreground=\"#888a85\">", newline(&out)),
+                                     strn!("</span>\n")),
+                    "pre" => {
+                        pre += 1;
+                        (format!("{}<tt>", newline(&out)), strn!("</tt>\n"))
+                    }
+                    "ul" => {
+                        lists.push(None);
+                        (String::new(), newline(&out))
+                    }
+                    "ol" => {
+                        let start = attr(&attrs, "start").and_then(|s| s.parse().ok()).unwrap_or(1);
+                        lists.push(Some(start));
+                        (String::new(), newline(&out))
+                    }
+                    "li" => {
+                        let indent = "    ".repeat(lists.len().saturating_sub(1));
+                        let bullet = match lists.last_mut() {
+                            Some(&mut Some(ref mut n)) => {
+                                *n += 1;
+                                format!("{}. ", *n - 1)
+                            }
+                            _ => strn!("• "),
+                        };
+                        (format!("{}{}{}", newline(&out), indent, bullet), strn!("\n"))
+                    }
+                    "a" => {
+                        match attr(&attrs, "href") {
+                            Some(ref href) if valid_href(href) => {
+                                // mentions are links to the user matrix.to url
+                                let mention = href.starts_with("https://matrix.to/#/@");
+                                (format!("<a href=\"{}\">{}", html_escape(href), if mention { "<b>" } else { "" }),
+                                 format!("{}</a>", if mention { "</b>" } else { "" }))
+                            }
+                            _ => (String::new(), String::new()),
+                        }
+                    }
+                    "font" | "span" => {
+                        let mut span = String::new();
+                        let fg = attr(&attrs, "data-mx-color").or(attr(&attrs, "color"));
+                        if let Some(c) = fg {
+                            if valid_color(&c) {
+                                span.push_str(&format!(" foreground=\"{}\"", c));
+                            }
+                        }
+                        if let Some(c) = attr(&attrs, "data-mx-bg-color") {
+                            if valid_color(&c) {
+                                span.push_str(&format!(" background=\"{}\"", c));
+                            }
+                        }
+                        if span.is_empty() {
+                            (String::new(), String::new())
+                        } else {
+                            (format!("<span{}>", span), strn!("</span>"))
+                        }
+                    }
+                    "br" => {
+                        out.push('\n');
+                        continue;
+                    }
+                    "hr" => {
+                        out.push_str(&format!("{}――――――――――\n", newline(&out)));
+                        continue;
+                    }
+                    // images can't be shown inside a label
+                    "img" => { return None; }
+                    _ => (String::new(), String::new()),
+                };
+
+                out.push_str(&open);
+                stack.push(Open { tag: tag, close: close });
+            }
+            Token::End(tag) => {
+                // closing the tags that aren't closed inside this one
+                if !stack.iter().any(|o| o.tag == tag) {
+                    continue;
+                }
+                while let Some(o) = stack.pop() {
+                    out.push_str(&o.close);
+                    match o.tag.as_str() {
+                        "pre" => { pre -= 1; }
+                        "ul" | "ol" => { lists.pop(); }
+                        _ => {}
+                    }
+                    if o.tag == tag {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    while let Some(o) = stack.pop() {
+        out.push_str(&o.close);
+    }
+
+    Some(strn!(out.trim()))
+}
+
+/// Splits the html in tags and text, the text entities are decoded.
+/// Returns None if there's a tag that isn't closed.
+fn tokenize(html: &str) -> Option<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut rest = html;
+
+    while !rest.is_empty() {
+        match rest.find('<') {
+            Some(0) => {
+                let end = tag_end(rest)?;
+                let tag = &rest[1..end];
+                rest = &rest[end + 1..];
+
+                // comments and doctypes
+                if tag.starts_with('!') || tag.starts_with('?') {
+                    continue;
+                }
+                if let Some(t) = parse_tag(tag) {
+                    tokens.push(t);
+                }
+            }
+            Some(n) => {
+                tokens.push(Token::Text(decode_entities(&rest[..n])));
+                rest = &rest[n..];
+            }
+            None => {
+                tokens.push(Token::Text(decode_entities(rest)));
+                rest = "";
+            }
+        }
+    }
+
+    Some(tokens)
+}
+
+/// Position of the '>' that closes the tag at the start of @s, ignoring the
+/// '>' inside quoted attribute values
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (None, '"') | (None, '\'') => { quote = Some(c); }
+            (Some(q), c) if q == c => { quote = None; }
+            (None, '>') => { return Some(i); }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn parse_tag(tag: &str) -> Option<Token> {
+    let tag = tag.trim().trim_right_matches('/');
+
+    if tag.starts_with('/') {
+        let name = tag[1..].trim().to_lowercase();
+        return Some(Token::End(name));
+    }
+
+    let name: String = tag.chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '-')
+        .collect();
+    if name.is_empty() {
+        return None;
+    }
+
+    let attrs: Vec<(String, String)> = ATTR_RE.with(|re| {
+        re.captures_iter(&tag[name.len()..]).map(|c| {
+            let value = c.get(2).or(c.get(3)).or(c.get(4)).map(|v| v.as_str()).unwrap_or("");
+            (c[1].to_lowercase(), decode_entities(value))
+        }).collect()
+    });
+
+    Some(Token::Start(name.to_lowercase(), attrs))
+}
+
+fn attr(attrs: &Vec<(String, String)>, name: &str) -> Option<String> {
+    attrs.iter().find(|a| a.0 == name).map(|a| a.1.clone())
+}
+
+fn decode_entities(s: &str) -> String {
+    ENTITY_RE.with(|re| re.replace_all(s, |c: &regex::Captures| {
+        let e = &c[1];
+        let decoded = match e {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ if e.starts_with("#x") || e.starts_with("#X") => {
+                u32::from_str_radix(&e[2..], 16).ok().and_then(::std::char::from_u32)
+            }
+            _ if e.starts_with('#') => {
+                e[1..].parse::<u32>().ok().and_then(::std::char::from_u32)
+            }
+            _ => None,
+        };
+
+        match decoded {
+            Some(ch) => ch.to_string(),
+            None => strn!(&c[0]),
+        }
+    }).to_string())
+}
+
+/// Outside of <pre> the html whitespace is shown as only one space
+fn collapse_spaces(s: &str, after_space: bool) -> String {
+    let mut out = String::new();
+    let mut space = after_space;
+    for c in s.chars() {
+        if c.is_whitespace() && c != '\u{a0}' {
+            if !space {
+                out.push(' ');
+            }
+            space = true;
+        } else {
+            out.push(c);
+            space = false;
+        }
+    }
+
+    out
+}
+
+/// New line to start a block, if we're not at the start of a line
+fn newline(out: &str) -> String {
+    if out.is_empty() || out.ends_with('\n') {
+        String::new()
+    } else {
+        strn!("\n")
+    }
+}
+
+fn valid_href(href: &str) -> bool {
+    href.starts_with("https://") || href.starts_with("http://") ||
+    href.starts_with("mailto:")
+}
+
+/// Only the hex colors, Pango doesn't know all the html color names and
+/// the markup with an unknown color can't be shown
+fn valid_color(color: &str) -> bool {
+    if !color.starts_with('#') {
+        return false;
+    }
+
+    let hex = &color[1..];
+    (hex.len() == 3 || hex.len() == 6) && hex.chars().all(|c| c.is_digit(16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_pango;
+
+    #[test]
+    fn disallowed_tags_keep_the_text() {
+        assert_eq!(to_pango("<marquee>hello</marquee> <b>world</b>"),
+                   Some(strn!("hello <b>world</b>")));
+    }
+
+    #[test]
+    fn hidden_tags_remove_the_content() {
+        assert_eq!(to_pango("<script>alert(1)</script>hi"), Some(strn!("hi")));
+        assert_eq!(to_pango("<mx-reply><blockquote>quoted</blockquote></mx-reply>answer"),
+                   Some(strn!("answer")));
+    }
+
+    #[test]
+    fn only_safe_links() {
+        assert_eq!(to_pango("<a href=\"javascript:alert(1)\">click</a>"),
+                   Some(strn!("click")));
+        assert_eq!(to_pango("<a href=\"https://gnome.org\">gnome</a>"),
+                   Some(strn!("<a href=\"https://gnome.org\">gnome</a>")));
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(to_pango("&lt;b&gt; &amp; &#65;&#x42; &unknown;"),
+                   Some(strn!("&lt;b&gt; &amp; AB &amp;unknown;")));
+    }
+
+    #[test]
+    fn only_hex_colors() {
+        assert_eq!(to_pango("<font color=\"#ff0000\">red</font>"),
+                   Some(strn!("<span foreground=\"#ff0000\">red</span>")));
+        assert_eq!(to_pango("<font color=\"rebeccapurple\">purple</font>"),
+                   Some(strn!("purple")));
+    }
+
+    #[test]
+    fn fallback_to_the_body() {
+        assert_eq!(to_pango("look <img src=\"mxc://server/id\">"), None);
+        assert_eq!(to_pango("<b>unclosed <i"), None);
+    }
+}
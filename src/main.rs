@@ -8,6 +8,8 @@ extern crate serde_json;
 extern crate serde_derive;
 #[macro_use]
 mod util;
+mod html;
+mod command;
 mod widgets;
 mod error;
 mod types;
@@ -6,7 +6,10 @@ use self::serde_json::Value as JsonValue;
 pub struct Event {
     pub sender: String,
     pub stype: String,
+    pub state_key: String,
     pub room: String,
     pub id: String,
     pub content: JsonValue,
+    // the redacted event id for m.room.redaction events
+    pub redacts: String,
 }
@@ -0,0 +1,118 @@
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+
+// Sync filter builder, the json value is uploaded to the server to get a
+// filter id that's used in the sync requests.
+//
+// https://matrix.org/docs/spec/client_server/r0.3.0.html#filtering
+#[derive(Debug, Clone)]
+pub struct SyncFilter {
+    timeline_limit: i32,
+    lazy_load_members: bool,
+    state: bool,
+    typing: bool,
+    receipts: bool,
+    presence: bool,
+    event_fields: Vec<String>,
+}
+
+impl SyncFilter {
+    pub fn new() -> SyncFilter {
+        SyncFilter {
+            timeline_limit: 10,
+            lazy_load_members: false,
+            state: true,
+            typing: false,
+            receipts: false,
+            presence: false,
+            event_fields: vec![],
+        }
+    }
+
+    pub fn timeline_limit(mut self, limit: i32) -> SyncFilter {
+        self.timeline_limit = limit;
+        self
+    }
+
+    /// Only the members of the senders of the timeline events are sent in
+    /// the room state.
+    pub fn lazy_load_members(mut self, lazy: bool) -> SyncFilter {
+        self.lazy_load_members = lazy;
+        self
+    }
+
+    /// Room state events, if false the room state section will be empty.
+    pub fn state(mut self, state: bool) -> SyncFilter {
+        self.state = state;
+        self
+    }
+
+    /// m.typing ephemeral events
+    pub fn typing(mut self, typing: bool) -> SyncFilter {
+        self.typing = typing;
+        self
+    }
+
+    /// m.receipt ephemeral events
+    pub fn receipts(mut self, receipts: bool) -> SyncFilter {
+        self.receipts = receipts;
+        self
+    }
+
+    /// m.presence events
+    pub fn presence(mut self, presence: bool) -> SyncFilter {
+        self.presence = presence;
+        self
+    }
+
+    /// The event fields that we want in the response, all fields if empty
+    pub fn event_fields(mut self, fields: Vec<&str>) -> SyncFilter {
+        self.event_fields = fields.iter().map(|f| String::from(*f)).collect();
+        self
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut ephemeral = vec![];
+        if self.typing {
+            ephemeral.push("m.typing");
+        }
+        if self.receipts {
+            ephemeral.push("m.receipt");
+        }
+
+        let presence = if self.presence {
+            vec!["m.presence"]
+        } else {
+            vec![]
+        };
+
+        let state = if self.state {
+            vec!["m.room.*"]
+        } else {
+            vec![]
+        };
+
+        let mut filter = json!({
+            "room": {
+                "state": {
+                    "types": state,
+                    "lazy_load_members": self.lazy_load_members,
+                },
+                "timeline": {
+                    "limit": self.timeline_limit,
+                    "lazy_load_members": self.lazy_load_members,
+                },
+                "ephemeral": { "types": ephemeral },
+            },
+            "presence": { "types": presence },
+            "event_format": "client",
+        });
+
+        if !self.event_fields.is_empty() {
+            filter["event_fields"] = json!(self.event_fields);
+        }
+
+        filter
+    }
+}
@@ -14,6 +14,24 @@ pub struct Message {
     pub thumb: String,
     pub url: String,
     pub id: String,
+    // pagination token to get the messages that we're missing before this
+    // one, after a limited sync
+    #[serde(default)]
+    pub gap: Option<String>,
+    // the message was removed, the content is empty
+    #[serde(default)]
+    pub redacted: bool,
+    // for m.replace edits, the id of the edited message
+    #[serde(default)]
+    pub replaces: Option<String>,
+    #[serde(default)]
+    pub edited: bool,
+    // org.matrix.custom.html formatted body
+    #[serde(default)]
+    pub formatted: Option<String>,
+    // the id of the message that this one replies to
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
 }
 
 impl Clone for Message {
@@ -27,6 +45,12 @@ impl Clone for Message {
             thumb: self.thumb.clone(),
             url: self.url.clone(),
             id: self.id.clone(),
+            gap: self.gap.clone(),
+            redacted: self.redacted,
+            replaces: self.replaces.clone(),
+            edited: self.edited,
+            formatted: self.formatted.clone(),
+            in_reply_to: self.in_reply_to.clone(),
         }
     }
 }
@@ -3,3 +3,7 @@ pub mod room;
 pub mod protocol;
 pub mod event;
 pub mod message;
+pub mod filter;
+pub mod receipt;
+pub mod powerlevels;
+pub mod reaction;
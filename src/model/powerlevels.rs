@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+fn default_level() -> i32 { 50 }
+
+/// m.room.power_levels content, the power level that each user has and the
+/// level needed to do things in the room. The missing fields take the
+/// default values of the spec.
+///
+/// https://matrix.org/docs/spec/client_server/r0.3.0.html#m-room-power-levels
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowerLevels {
+    #[serde(default = "default_level")]
+    pub ban: i32,
+    #[serde(default = "default_level")]
+    pub kick: i32,
+    #[serde(default = "default_level")]
+    pub redact: i32,
+    #[serde(default = "default_level")]
+    pub invite: i32,
+    #[serde(default)]
+    pub events: HashMap<String, i32>,
+    #[serde(default)]
+    pub events_default: i32,
+    #[serde(default = "default_level")]
+    pub state_default: i32,
+    #[serde(default)]
+    pub users: HashMap<String, i32>,
+    #[serde(default)]
+    pub users_default: i32,
+}
+
+impl PowerLevels {
+    pub fn new() -> PowerLevels {
+        PowerLevels {
+            ban: default_level(),
+            kick: default_level(),
+            redact: default_level(),
+            invite: default_level(),
+            events: HashMap::new(),
+            events_default: 0,
+            state_default: default_level(),
+            users: HashMap::new(),
+            users_default: 0,
+        }
+    }
+
+    pub fn user_level(&self, uid: &str) -> i32 {
+        match self.users.get(uid) {
+            Some(l) => *l,
+            None => self.users_default,
+        }
+    }
+
+    /// The level needed to send this event type, state events use the
+    /// state_default if they're not in the events list
+    pub fn event_level(&self, evtype: &str, state: bool) -> i32 {
+        match self.events.get(evtype) {
+            Some(l) => *l,
+            None if state => self.state_default,
+            None => self.events_default,
+        }
+    }
+}
+
+/// The power levels changed in the room settings. Only these are written
+/// over the current content, so we don't revert the changes made by other
+/// admins since our last sync.
+#[derive(Debug, Clone)]
+pub struct PowerLevelsChanges {
+    // top level fields, like "ban" or "users_default"
+    pub levels: HashMap<String, i32>,
+    pub events: HashMap<String, i32>,
+    pub users: HashMap<String, i32>,
+}
+
+impl PowerLevelsChanges {
+    pub fn new() -> PowerLevelsChanges {
+        PowerLevelsChanges {
+            levels: HashMap::new(),
+            events: HashMap::new(),
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.levels.is_empty() && self.events.is_empty() && self.users.is_empty()
+    }
+}
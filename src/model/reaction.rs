@@ -0,0 +1,14 @@
+use std::collections::HashMap;
+
+/// m.reaction event, an annotation with a key, usually an emoji, over other
+/// event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub id: String,
+    pub sender: String,
+    pub target: String,
+    pub key: String,
+}
+
+// hashmap target event id -> Reactions
+pub type ReactionList = HashMap<String, Vec<Reaction>>;
@@ -0,0 +1,15 @@
+extern crate chrono;
+
+use self::chrono::prelude::*;
+use std::collections::HashMap;
+
+/// The last event read by an user in a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Receipt {
+    pub uid: String,
+    pub event: String,
+    pub date: DateTime<Local>,
+}
+
+// hashmap userid -> Receipt
+pub type ReceiptList = HashMap<String, Receipt>;
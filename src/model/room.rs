@@ -1,5 +1,9 @@
 use std::collections::HashMap;
 use model::message::Message;
+use model::member::Member;
+use model::receipt::ReceiptList;
+use model::powerlevels::PowerLevels;
+use model::reaction::ReactionList;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Room {
@@ -12,8 +16,30 @@ pub struct Room {
     pub world_readable: bool,
     pub members: i32,
     pub notifications: i32,
+    #[serde(default)]
+    pub highlight: i32,
     pub messages: Vec<Message>,
     pub batch_end: String,
+    /// the last message read by each member
+    #[serde(default)]
+    pub receipts: ReceiptList,
+    /// the m.fully_read marker, the last message that we've read
+    #[serde(default)]
+    pub fully_read: String,
+    #[serde(default = "PowerLevels::new")]
+    pub power_levels: PowerLevels,
+    /// the reactions to each message
+    #[serde(default)]
+    pub reactions: ReactionList,
+    /// true if this is a direct chat, from the m.direct account data
+    #[serde(default)]
+    pub direct: bool,
+
+    /// true if this is an invitation that we haven't accepted yet
+    #[serde(default)]
+    pub inv: bool,
+    #[serde(default)]
+    pub inv_sender: Option<Member>,
 }
 
 impl Room {
@@ -28,8 +54,16 @@ impl Room {
             world_readable: true,
             members: 0,
             notifications: 0,
+            highlight: 0,
             messages: vec![],
             batch_end: String::new(),
+            receipts: HashMap::new(),
+            fully_read: String::new(),
+            power_levels: PowerLevels::new(),
+            reactions: HashMap::new(),
+            direct: false,
+            inv: false,
+            inv_sender: None,
         }
     }
 }
@@ -46,10 +80,30 @@ impl Clone for Room {
             world_readable: self.world_readable,
             members: self.members,
             notifications: self.notifications,
+            highlight: self.highlight,
             messages: self.messages.iter().cloned().collect(),
             batch_end: self.batch_end.clone(),
+            receipts: self.receipts.clone(),
+            fully_read: self.fully_read.clone(),
+            power_levels: self.power_levels.clone(),
+            reactions: self.reactions.clone(),
+            direct: self.direct,
+            inv: self.inv,
+            inv_sender: self.inv_sender.clone(),
         }
     }
 }
 
 pub type RoomList = HashMap<String, Room>;
+
+/// The options of a room to create
+#[derive(Debug, Clone)]
+pub struct NewRoom {
+    pub name: String,
+    pub topic: String,
+    /// the local part of the alias, without the '#' and the server
+    pub alias: String,
+    pub public: bool,
+    pub preset: String,
+    pub invites: Vec<String>,
+}
@@ -1,7 +1,15 @@
 pub use model::event::Event;
 pub use model::room::Room;
 pub use model::room::RoomList;
+pub use model::room::NewRoom;
 pub use model::protocol::Protocol;
 pub use model::message::Message;
 pub use model::member::Member;
 pub use model::member::MemberList;
+pub use model::filter::SyncFilter;
+pub use model::receipt::Receipt;
+pub use model::receipt::ReceiptList;
+pub use model::powerlevels::PowerLevels;
+pub use model::powerlevels::PowerLevelsChanges;
+pub use model::reaction::Reaction;
+pub use model::reaction::ReactionList;
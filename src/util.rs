@@ -10,11 +10,13 @@ extern crate gdk;
 extern crate gdk_pixbuf;
 extern crate mime;
 extern crate tree_magic;
+extern crate comrak;
 
 use self::gdk_pixbuf::Pixbuf;
 use self::gdk::ContextExt;
 
 use self::regex::Regex;
+use self::comrak::ComrakOptions;
 
 use self::serde_json::Value as JsonValue;
 
@@ -26,6 +28,7 @@ use std::path::PathBuf;
 use std::fs::File;
 use std::io::prelude::*;
 
+use std::collections::HashMap;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
@@ -37,6 +40,10 @@ use error::Error;
 use types::Message;
 use types::Room;
 use types::Event;
+use types::Member;
+use types::Receipt;
+use types::PowerLevels;
+use types::Reaction;
 
 use self::reqwest::header::ContentType;
 use self::mime::Mime;
@@ -201,32 +208,173 @@ pub fn evc(events: &JsonValue, t: &str, field: &str) -> String {
 
 pub fn get_rooms_from_json(r: JsonValue, userid: &str, baseu: &Url) -> Result<Vec<Room>, Error> {
     let rooms = &r["rooms"];
-    // TODO: do something with invite and leave
-    //let invite = rooms["invite"].as_object().ok_or(Error::BackendError)?;
-    //let leave = rooms["leave"].as_object().ok_or(Error::BackendError)?;
-
+    // left rooms aren't in the initial sync, those are managed in the
+    // incremental sync, look at get_left_rooms_from_json
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
 
+    let empty = vec![];
+
     let mut rooms: Vec<Room> = vec![];
     for k in join.keys() {
         let room = join.get(k).ok_or(Error::BackendError)?;
-        let stevents = &room["state"]["events"];
+        let mut r = parse_joined_room(k, room, userid)?;
+
         let timeline = &room["timeline"];
+        let events = timeline["events"].as_array().unwrap_or(&empty);
+        let msgs = events.iter()
+            .filter(|x| x["type"] == "m.room.message")
+            .map(|ev| parse_room_message(baseu, k.clone(), ev))
+            .collect();
+        r.messages = apply_edits(msgs);
+
+        for ev in events.iter().filter(|x| x["type"] == "m.reaction") {
+            let id = ev["event_id"].as_str().unwrap_or("");
+            let sender = ev["sender"].as_str().unwrap_or("");
+            if let Some(reaction) = parse_reaction(id, sender, &ev["content"]) {
+                r.reactions.entry(reaction.target.clone()).or_insert(vec![]).push(reaction);
+            }
+        }
+
+        rooms.push(r);
+    }
+
+    rooms.extend(get_invited_rooms_from_json(&r, userid)?);
+
+    if let Some(direct) = get_direct_from_json(&r) {
+        for room in rooms.iter_mut().filter(|r| !r.inv) {
+            room.direct = is_direct(&direct, &room.id);
+        }
+    }
+
+    Ok(rooms)
+}
+
+/// Returns the m.direct account data of the sync response, the rooms of
+/// the direct chats with each user. None if it hasn't changed.
+pub fn get_direct_from_json(r: &JsonValue) -> Option<HashMap<String, Vec<String>>> {
+    let empty = vec![];
+    let events = r["account_data"]["events"].as_array().unwrap_or(&empty);
+    let ev = match events.iter().find(|x| x["type"] == "m.direct") {
+        Some(ev) => ev,
+        None => { return None; }
+    };
+
+    Some(get_direct_from_content(&ev["content"]))
+}
+
+/// Parses the content of the m.direct account data, user id -> rooms
+pub fn get_direct_from_content(content: &JsonValue) -> HashMap<String, Vec<String>> {
+    let empty = vec![];
+    let mut direct = HashMap::new();
+    if let Some(users) = content.as_object() {
+        for (uid, rooms) in users.iter() {
+            let rooms = rooms.as_array().unwrap_or(&empty).iter()
+                .filter_map(|r| r.as_str())
+                .map(|r| strn!(r))
+                .collect();
+            direct.insert(uid.clone(), rooms);
+        }
+    }
+
+    direct
+}
+
+/// Looks like a matrix user id, @user:server
+pub fn is_uid(uid: &str) -> bool {
+    uid.starts_with('@') && uid.contains(':')
+}
+
+pub fn is_direct(direct: &HashMap<String, Vec<String>>, roomid: &str) -> bool {
+    direct.values().any(|rooms| rooms.iter().any(|r| r == roomid))
+}
+
+/// Adds the room to the direct chats with the user in the m.direct account
+/// data, and returns the new content.
+///
+/// The content is replaced with the PUT, so we start from the current one
+/// and not from the one that we've got in the last sync, that could be
+/// outdated. Without it we could remove the direct chats of other sessions.
+pub fn add_direct_room(url: &Url, userid: &str, roomid: &str) -> Result<HashMap<String, Vec<String>>, Error> {
+    let mut direct = match json_q("get", url, &json!(null), timeout!()) {
+        Ok(js) => get_direct_from_content(&js),
+        Err(Error::MatrixError(ref js)) if js["errcode"].as_str().unwrap_or("") == "M_NOT_FOUND" => {
+            HashMap::new()
+        }
+        Err(err) => { return Err(err); }
+    };
+
+    {
+        let rooms = direct.entry(strn!(userid)).or_insert(vec![]);
+        if !rooms.iter().any(|r| r == roomid) {
+            rooms.push(strn!(roomid));
+        }
+    }
+
+    json_q("put", url, &json!(direct), timeout!())?;
+
+    Ok(direct)
+}
+
+/// Looks for the rooms that the user has joined or has been invited to since
+/// the last sync.
+///
+/// A joined room is new when the sync contains our own join membership event
+/// and the previous membership wasn't a join, so profile changes, that are
+/// join events too, aren't considered new rooms.
+///
+/// The returned rooms doesn't have messages, those are sent with the other
+/// timeline messages of the sync.
+pub fn get_new_rooms_from_json(r: &JsonValue, userid: &str) -> Result<Vec<Room>, Error> {
+    let rooms = &r["rooms"];
+    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+    let empty = vec![];
+
+    let mut newrooms: Vec<Room> = vec![];
+    for k in join.keys() {
+        let room = join.get(k).ok_or(Error::BackendError)?;
+        let stevents = room["state"]["events"].as_array().unwrap_or(&empty);
+        let tlevents = room["timeline"]["events"].as_array().unwrap_or(&empty);
+
+        let joined = stevents.iter().chain(tlevents.iter()).any(|x| {
+            x["type"] == "m.room.member" &&
+            x["sender"] == userid &&
+            x["content"]["membership"] == "join" &&
+            x["unsigned"]["prev_content"]["membership"] != "join"
+        });
+
+        if joined {
+            newrooms.push(parse_joined_room(k, room, userid)?);
+        }
+    }
+
+    newrooms.extend(get_invited_rooms_from_json(r, userid)?);
+
+    Ok(newrooms)
+}
+
+/// Invites are rooms with the `inv` flag, built from the stripped state
+/// that we receive in the `invite_state` of the sync response.
+pub fn get_invited_rooms_from_json(r: &JsonValue, userid: &str) -> Result<Vec<Room>, Error> {
+    let mut rooms: Vec<Room> = vec![];
+    let invite = match r["rooms"]["invite"].as_object() {
+        Some(i) => i,
+        None => return Ok(rooms),
+    };
+
+    for k in invite.keys() {
+        let room = invite.get(k).ok_or(Error::BackendError)?;
+        let stevents = &room["invite_state"]["events"];
         let name = calculate_room_name(stevents, userid)?;
         let mut r = Room::new(k.clone(), name);
 
         r.avatar = evc(stevents, "m.room.avatar", "url");
         r.alias = evc(stevents, "m.room.canonical_alias", "alias");
         r.topic = evc(stevents, "m.room.topic", "topic");
-        r.notifications = room["unread_notifications"]["notification_count"]
-            .as_i64()
-            .unwrap_or(0) as i32;
-
-        r.batch_end = strn!(timeline["prev_batch"].as_str().unwrap_or(""));
-        for ev in timeline["events"].as_array().unwrap_or(&vec![]) {
-            let msg = parse_room_message(baseu, k.clone(), ev);
-            r.messages.push(msg);
-        }
+        r.inv = true;
+        r.inv_sender = get_inviter(stevents, userid);
+        // the invite is for a direct chat with the inviter, we add it to
+        // the m.direct when it's accepted
+        r.direct = is_direct_invite(stevents, userid);
 
         rooms.push(r);
     }
@@ -234,62 +382,373 @@ pub fn get_rooms_from_json(r: JsonValue, userid: &str, baseu: &Url) -> Result<Ve
     Ok(rooms)
 }
 
-pub fn get_rooms_timeline_from_json(baseu: &Url, r: &JsonValue) -> Result<Vec<Message>, Error> {
+/// True if our invite membership event is marked as a direct chat
+pub fn is_direct_invite(stevents: &JsonValue, userid: &str) -> bool {
+    let empty = vec![];
+    stevents.as_array().unwrap_or(&empty).iter().any(|x| {
+        x["type"] == "m.room.member" &&
+        x["state_key"] == userid &&
+        x["content"]["membership"] == "invite" &&
+        x["content"]["is_direct"] == true
+    })
+}
+
+/// Looks for the member that has sent our invite membership event in the
+/// room stripped state.
+pub fn get_inviter(stevents: &JsonValue, userid: &str) -> Option<Member> {
+    let events = match stevents.as_array() {
+        Some(evs) => evs,
+        None => return None,
+    };
+
+    let inv = match events.iter().find(|x| {
+        x["type"] == "m.room.member" &&
+        x["state_key"] == userid &&
+        x["content"]["membership"] == "invite"
+    }) {
+        Some(ev) => ev,
+        None => return None,
+    };
+
+    let sender = inv["sender"].as_str().unwrap_or("");
+    let mut m = Member {
+        alias: String::new(),
+        uid: strn!(sender),
+        avatar: String::new(),
+    };
+
+    let sendermember = events.iter().find(|x| {
+        x["type"] == "m.room.member" && x["state_key"] == sender
+    });
+    if let Some(ev) = sendermember {
+        m.alias = strn!(ev["content"]["displayname"].as_str().unwrap_or(""));
+        m.avatar = strn!(ev["content"]["avatar_url"].as_str().unwrap_or(""));
+    }
+
+    Some(m)
+}
+
+/// Looks for the rooms that we've left since the last sync, because we've
+/// left from other device, we've been kicked or banned or the invitation
+/// was rejected or withdrawn.
+///
+/// For each room it returns our last membership event in that room, if it's
+/// in the sync, so we can know who and why we're out of the room.
+pub fn get_left_rooms_from_json(r: &JsonValue, userid: &str) -> Result<Vec<(String, Option<Event>)>, Error> {
+    let mut rooms = vec![];
+    let leave = match r["rooms"]["leave"].as_object() {
+        Some(l) => l,
+        None => return Ok(rooms),
+    };
+    let empty = vec![];
+
+    for k in leave.keys() {
+        let room = leave.get(k).ok_or(Error::BackendError)?;
+        let stevents = room["state"]["events"].as_array().unwrap_or(&empty);
+        let tlevents = room["timeline"]["events"].as_array().unwrap_or(&empty);
+
+        let ev = stevents.iter().chain(tlevents.iter())
+            .filter(|x| x["type"] == "m.room.member" && x["state_key"] == userid)
+            .last()
+            .map(|x| parse_event(k, x));
+
+        rooms.push((k.clone(), ev));
+    }
+
+    Ok(rooms)
+}
+
+fn parse_joined_room(roomid: &str, room: &JsonValue, userid: &str) -> Result<Room, Error> {
+    let stevents = &room["state"]["events"];
+    let timeline = &room["timeline"];
+    let name = calculate_room_name(stevents, userid)?;
+    let mut r = Room::new(strn!(roomid), name);
+
+    r.avatar = evc(stevents, "m.room.avatar", "url");
+    r.alias = evc(stevents, "m.room.canonical_alias", "alias");
+    r.topic = evc(stevents, "m.room.topic", "topic");
+    r.notifications = room["unread_notifications"]["notification_count"]
+        .as_i64()
+        .unwrap_or(0) as i32;
+    r.highlight = room["unread_notifications"]["highlight_count"]
+        .as_i64()
+        .unwrap_or(0) as i32;
+
+    r.batch_end = strn!(timeline["prev_batch"].as_str().unwrap_or(""));
+
+    let empty = vec![];
+    let ephemeral = room["ephemeral"]["events"].as_array().unwrap_or(&empty);
+    for ev in ephemeral.iter().filter(|x| x["type"] == "m.receipt") {
+        for receipt in parse_receipts(ev) {
+            r.receipts.insert(receipt.uid.clone(), receipt);
+        }
+    }
+
+    r.fully_read = evc(&room["account_data"]["events"], "m.fully_read", "event_id");
+
+    if let Some(arr) = stevents.as_array() {
+        if let Some(ev) = arr.iter().find(|x| x["type"] == "m.room.power_levels") {
+            r.power_levels = parse_power_levels(&ev["content"]);
+        }
+    }
+
+    Ok(r)
+}
+
+pub fn get_rooms_timeline_from_json(baseu: &Url, r: &JsonValue) -> Result<(Vec<Message>, Vec<(String, String)>), Error> {
     let rooms = &r["rooms"];
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
 
     let mut msgs: Vec<Message> = vec![];
+    // the gaps of the limited timelines without messages
+    let mut gaps: Vec<(String, String)> = vec![];
     for k in join.keys() {
         let room = join.get(k).ok_or(Error::BackendError)?;
         let timeline = room["timeline"]["events"].as_array();
         if timeline.is_none() {
-            return Ok(msgs);
+            continue;
         }
 
         let events = timeline.unwrap()
             .iter()
             .filter(|x| x["type"] == "m.room.message");
 
+        // if the timeline is limited, there're messages between the last
+        // sync and this one that we don't have, so we mark the first one
+        // with the token to get the missing messages
+        let mut gap = match room["timeline"]["limited"].as_bool() {
+            Some(true) => room["timeline"]["prev_batch"].as_str().map(|t| strn!(t)),
+            _ => None,
+        };
+
         for ev in events {
-            let msg = parse_room_message(baseu, k.clone(), ev);
+            let mut msg = parse_room_message(baseu, k.clone(), ev);
+            msg.gap = gap.take();
             msgs.push(msg);
         }
+
+        if let Some(token) = gap {
+            gaps.push((k.clone(), token));
+        }
+    }
+
+    Ok((msgs, gaps))
+}
+
+/// Returns the unread notifications and highlight count of each joined room
+/// in the sync response.
+pub fn get_rooms_notifies_from_json(r: &JsonValue) -> Result<Vec<(String, i32, i32)>, Error> {
+    let rooms = &r["rooms"];
+    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+
+    let mut out = vec![];
+    for k in join.keys() {
+        let room = join.get(k).ok_or(Error::BackendError)?;
+        let unread = &room["unread_notifications"];
+        if unread.is_null() {
+            continue;
+        }
+
+        let n = unread["notification_count"].as_i64().unwrap_or(0) as i32;
+        let h = unread["highlight_count"].as_i64().unwrap_or(0) as i32;
+        out.push((k.clone(), n, h));
+    }
+
+    Ok(out)
+}
+
+/// Returns the list of users typing for each joined room that has a m.typing
+/// ephemeral event in this sync. The list is the full list of typing users,
+/// so an empty list means that nobody is typing now.
+pub fn get_typing_from_json(r: &JsonValue) -> Result<Vec<(String, Vec<String>)>, Error> {
+    let rooms = &r["rooms"];
+    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+    let empty = vec![];
+
+    let mut out = vec![];
+    for k in join.keys() {
+        let room = join.get(k).ok_or(Error::BackendError)?;
+        let ephemeral = room["ephemeral"]["events"].as_array().unwrap_or(&empty);
+        for ev in ephemeral.iter().filter(|x| x["type"] == "m.typing") {
+            let users = ev["content"]["user_ids"].as_array().unwrap_or(&empty);
+            let users = users.iter()
+                .filter_map(|u| u.as_str())
+                .map(|u| String::from(u))
+                .collect();
+            out.push((k.clone(), users));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Returns the new read receipts of each joined room in the sync response.
+pub fn get_receipts_from_json(r: &JsonValue) -> Result<Vec<(String, Vec<Receipt>)>, Error> {
+    let rooms = &r["rooms"];
+    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+    let empty = vec![];
+
+    let mut out = vec![];
+    for k in join.keys() {
+        let room = join.get(k).ok_or(Error::BackendError)?;
+        let ephemeral = room["ephemeral"]["events"].as_array().unwrap_or(&empty);
+        let receipts: Vec<Receipt> = ephemeral.iter()
+            .filter(|x| x["type"] == "m.receipt")
+            .flat_map(|ev| parse_receipts(ev))
+            .collect();
+
+        if !receipts.is_empty() {
+            out.push((k.clone(), receipts));
+        }
     }
 
-    Ok(msgs)
+    Ok(out)
 }
 
+/// Returns the m.fully_read marker of each joined room that has changed it
+/// since the last sync.
+pub fn get_fully_read_from_json(r: &JsonValue) -> Result<Vec<(String, String)>, Error> {
+    let rooms = &r["rooms"];
+    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+
+    let mut out = vec![];
+    for k in join.keys() {
+        let room = join.get(k).ok_or(Error::BackendError)?;
+        let evid = evc(&room["account_data"]["events"], "m.fully_read", "event_id");
+        if !evid.is_empty() {
+            out.push((k.clone(), evid));
+        }
+    }
+
+    Ok(out)
+}
+
+/// Returns the m.presence events of the sync response as a list of
+/// (user id, presence, status message)
+pub fn get_presence_from_json(r: &JsonValue) -> Vec<(String, String, String)> {
+    let empty = vec![];
+    let events = r["presence"]["events"].as_array().unwrap_or(&empty);
+
+    events.iter().filter(|x| x["type"] == "m.presence").map(|ev| {
+        let c = &ev["content"];
+        let uid = ev["sender"].as_str().or(c["user_id"].as_str()).unwrap_or("");
+        let presence = c["presence"].as_str().unwrap_or("offline");
+        let status = c["status_msg"].as_str().unwrap_or("");
+        (strn!(uid), strn!(presence), strn!(status))
+    }).collect()
+}
+
+/// Pango markup of the dot that shows the presence of an user, empty if we
+/// don't know it
+pub fn presence_markup(presence: &str) -> String {
+    let color = match presence {
+        "online" => "#73d216",
+        "unavailable" => "#f57900",
+        "offline" => "#babdb6",
+        _ => { return String::new(); }
+    };
+
+    format!("<span color=\"{}\">●</span>", color)
+}
+
+/// Parses a m.receipt event content, that's a map of event id to the
+/// users that have read it:
+///
+/// { "$event": { "m.read": { "@user:server": { "ts": 1436451550453 } } } }
+pub fn parse_receipts(ev: &JsonValue) -> Vec<Receipt> {
+    let mut receipts = vec![];
+
+    let content = match ev["content"].as_object() {
+        Some(c) => c,
+        None => { return receipts; }
+    };
+
+    for (evid, r) in content.iter() {
+        let users = match r["m.read"].as_object() {
+            Some(u) => u,
+            None => { continue; }
+        };
+
+        for (uid, info) in users.iter() {
+            let ts = info["ts"].as_i64().unwrap_or(0);
+            receipts.push(Receipt {
+                uid: uid.clone(),
+                event: evid.clone(),
+                date: ts_to_datetime(ts),
+            });
+        }
+    }
+
+    receipts
+}
+
+/// Returns all the events that aren't messages of the joined rooms, from the
+/// state and the timeline sections of the sync response.
+///
+/// The state section events go first, because those are the state at the
+/// start of the timeline.
 pub fn parse_sync_events(r: &JsonValue) -> Result<Vec<Event>, Error> {
     let rooms = &r["rooms"];
     let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+    let empty = vec![];
 
     let mut evs: Vec<Event> = vec![];
     for k in join.keys() {
         let room = join.get(k).ok_or(Error::BackendError)?;
-        let timeline = room["timeline"]["events"].as_array();
-        if timeline.is_none() {
-            return Ok(evs);
-        }
+        let stevents = room["state"]["events"].as_array().unwrap_or(&empty);
+        let tlevents = room["timeline"]["events"].as_array().unwrap_or(&empty);
 
-        let events = timeline.unwrap()
-            .iter()
+        let events = stevents.iter()
+            .chain(tlevents.iter())
             .filter(|x| x["type"] != "m.room.message");
 
         for ev in events {
-            //println!("ev: {:#?}", ev);
-            evs.push(Event {
-                room: k.clone(),
-                sender: strn!(ev["sender"].as_str().unwrap_or("")),
-                content: ev["content"].clone(),
-                stype: strn!(ev["type"].as_str().unwrap_or("")),
-                id: strn!(ev["id"].as_str().unwrap_or("")),
-            });
+            evs.push(parse_event(k, ev));
         }
     }
 
     Ok(evs)
 }
 
+/// Parses a m.reaction event content, None if it isn't an annotation
+pub fn parse_reaction(id: &str, sender: &str, content: &JsonValue) -> Option<Reaction> {
+    let rel = &content["m.relates_to"];
+    if rel["rel_type"] != "m.annotation" {
+        return None;
+    }
+
+    let target = rel["event_id"].as_str().unwrap_or("");
+    let key = rel["key"].as_str().unwrap_or("");
+    if target.is_empty() || key.is_empty() {
+        return None;
+    }
+
+    Some(Reaction {
+        id: strn!(id),
+        sender: strn!(sender),
+        target: strn!(target),
+        key: strn!(key),
+    })
+}
+
+/// Parses the m.room.power_levels content, the spec defaults are used if
+/// it isn't valid
+pub fn parse_power_levels(content: &JsonValue) -> PowerLevels {
+    serde_json::from_value(content.clone()).unwrap_or(PowerLevels::new())
+}
+
+pub fn parse_event(roomid: &str, ev: &JsonValue) -> Event {
+    Event {
+        room: strn!(roomid),
+        sender: strn!(ev["sender"].as_str().unwrap_or("")),
+        content: ev["content"].clone(),
+        stype: strn!(ev["type"].as_str().unwrap_or("")),
+        state_key: strn!(ev["state_key"].as_str().unwrap_or("")),
+        id: strn!(ev["event_id"].as_str().unwrap_or("")),
+        redacts: strn!(ev["redacts"].as_str().unwrap_or("")),
+    }
+}
+
 pub fn get_media(url: &str) -> Result<Vec<u8>, Error> {
     let client = reqwest::Client::new();
     let mut conn = client.get(url);
@@ -376,6 +835,11 @@ pub fn age_to_datetime(age: i64) -> DateTime<Local> {
     now - diff
 }
 
+/// Converts a timestamp in milliseconds since the epoch to a local datetime
+pub fn ts_to_datetime(ts: i64) -> DateTime<Local> {
+    Local.timestamp(ts / 1000, ((ts % 1000) * 1000000) as u32)
+}
+
 pub fn json_q(method: &str, url: &Url, attrs: &JsonValue, timeout: u64) -> Result<JsonValue, Error> {
     let mut clientb = reqwest::ClientBuilder::new();
     let client = match timeout {
@@ -593,9 +1057,34 @@ pub fn parse_room_message(baseu: &Url, roomid: String, msg: &JsonValue) -> Messa
 
     let id = msg["event_id"].as_str().unwrap_or("");
 
-    let c = &msg["content"];
+    let mut c = &msg["content"];
+
+    // edits have the new content in m.new_content, the body is a fallback
+    // for the clients that doesn't support edits
+    let mut replaces = None;
+    if c["m.relates_to"]["rel_type"] == "m.replace" {
+        replaces = c["m.relates_to"]["event_id"].as_str().map(|id| strn!(id));
+        if c["m.new_content"].is_object() {
+            c = &c["m.new_content"];
+        }
+    }
+
     let mtype = c["msgtype"].as_str().unwrap_or("");
-    let body = c["body"].as_str().unwrap_or("");
+    let mut body = strn!(c["body"].as_str().unwrap_or(""));
+
+    let mut formatted = match c["format"].as_str() {
+        Some("org.matrix.custom.html") => c["formatted_body"].as_str().map(|f| strn!(f)),
+        _ => None,
+    };
+
+    // the quoted message is shown from the original message, so the reply
+    // fallback is removed
+    let in_reply_to = msg["content"]["m.relates_to"]["m.in_reply_to"]["event_id"]
+        .as_str().map(|id| strn!(id));
+    if in_reply_to.is_some() {
+        body = strip_reply_fallback(&body);
+        formatted = formatted.map(|f| strip_reply_fallback_html(&f));
+    }
 
     let mut url = String::new();
     let mut thumb = String::new();
@@ -615,15 +1104,140 @@ pub fn parse_room_message(baseu: &Url, roomid: String, msg: &JsonValue) -> Messa
     Message {
         sender: String::from(sender),
         mtype: String::from(mtype),
-        body: String::from(body),
+        body: body,
         date: age_to_datetime(age),
         room: roomid.clone(),
         url: url,
         thumb: thumb,
         id: String::from(id),
+        gap: None,
+        redacted: msg["unsigned"]["redacted_because"].is_object(),
+        replaces: replaces,
+        edited: false,
+        formatted: formatted,
+        in_reply_to: in_reply_to,
+    }
+}
+
+/// Removes the quoted lines at the start of a reply body:
+///
+/// > <@alice:example.org> This is the original body
+///
+/// This is where the reply goes
+pub fn strip_reply_fallback(body: &str) -> String {
+    let lines: Vec<&str> = body.lines()
+        .skip_while(|l| l.starts_with("> ") || *l == ">")
+        .collect();
+
+    strn!(lines.join("\n").trim_left())
+}
+
+/// Removes the <mx-reply> block of a reply formatted body
+pub fn strip_reply_fallback_html(formatted: &str) -> String {
+    match formatted.find("</mx-reply>") {
+        Some(end) => strn!(&formatted[end + "</mx-reply>".len()..]),
+        None => strn!(formatted),
+    }
+}
+
+/// Builds the fallback body and formatted body of a reply to @parent, for
+/// the clients that doesn't show replies
+pub fn reply_fallback(parent: &Message, body: &str, formatted: &str) -> (String, String) {
+    let mut quoted = vec![];
+    for (i, l) in parent.body.lines().enumerate() {
+        match i {
+            0 => quoted.push(format!("> <{}> {}", parent.sender, l)),
+            _ => quoted.push(format!("> {}", l)),
+        }
+    }
+    let fallback = format!("{}\n\n{}", quoted.join("\n"), body);
+
+    let parent_html = match parent.formatted {
+        Some(ref f) => f.clone(),
+        None => html_escape(&parent.body).replace("\n", "<br>"),
+    };
+    let fallback_html = format!("<mx-reply><blockquote>\
+                                 <a href=\"https://matrix.to/#/{room}/{id}\">In reply to</a> \
+                                 <a href=\"https://matrix.to/#/{sender}\">{sender}</a><br>\
+                                 {parent}</blockquote></mx-reply>{body}",
+                                room = parent.room, id = parent.id,
+                                sender = parent.sender, parent = parent_html,
+                                body = formatted);
+
+    (fallback, fallback_html)
+}
+
+/// Escapes the html special characters
+pub fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+     .replace('<', "&lt;")
+     .replace('>', "&gt;")
+     .replace('"', "&quot;")
+}
+
+/// Renders the markdown @body as html, returns None if the body doesn't
+/// have any formatting so it can be sent only as plain text
+pub fn markdown_to_html(body: &str) -> Option<String> {
+    let opts = ComrakOptions {
+        hardbreaks: true,
+        ext_strikethrough: true,
+        ..ComrakOptions::default()
+    };
+
+    let html = comrak::markdown_to_html(body, &opts);
+    let html = html.trim();
+
+    // a plain text is rendered as only one paragraph
+    let inner = if html.starts_with("<p>") && html.ends_with("</p>") &&
+                   html.matches("<p>").count() == 1 {
+        &html[3..html.len() - 4]
+    } else {
+        html
+    };
+    let plain = html_escape(body.trim()).replace('\n', "<br />\n");
+
+    if inner == plain {
+        None
+    } else {
+        Some(strn!(html))
+    }
+}
+
+/// Replaces the content of the message edited by the @edit with the new
+/// content, only the sender of a message can edit it.
+///
+/// Returns false if the edited message isn't in the list.
+pub fn apply_edit(msgs: &mut Vec<Message>, edit: &Message) -> bool {
+    let target = match edit.replaces {
+        Some(ref t) => t,
+        None => { return false; }
+    };
+
+    match msgs.iter_mut().find(|m| m.id == *target && m.sender == edit.sender) {
+        Some(m) => {
+            m.body = edit.body.clone();
+            m.formatted = edit.formatted.clone();
+            m.mtype = edit.mtype.clone();
+            m.edited = true;
+            true
+        }
+        None => false,
     }
 }
 
+/// Applies the edits in the list to the messages of the same list, the
+/// edits are removed from the returned list.
+pub fn apply_edits(msgs: Vec<Message>) -> Vec<Message> {
+    let (edits, mut msgs): (Vec<Message>, Vec<Message>) = msgs.into_iter()
+        .partition(|m| m.replaces.is_some());
+
+    for e in edits.iter() {
+        apply_edit(&mut msgs, e);
+    }
+
+    msgs
+}
+
 pub fn markup(s: &str) -> String {
     let mut out = String::from(s);
 
@@ -649,9 +1263,10 @@ pub fn get_initial_room_messages(baseu: &Url,
                                  get: usize,
                                  limit: i32,
                                  end: Option<String>)
-                                 -> Result<(Vec<Message>, String, String), Error> {
+                                 -> Result<(Vec<Message>, Vec<Reaction>, String, String), Error> {
 
     let mut ms: Vec<Message> = vec![];
+    let mut reactions: Vec<Reaction> = vec![];
     let mut nstart;
     let mut nend;
 
@@ -675,10 +1290,18 @@ pub fn get_initial_room_messages(baseu: &Url,
 
     let array = r["chunk"].as_array();
     if array.is_none() || array.unwrap().len() == 0 {
-        return Ok((ms, nstart, nend));
+        return Ok((ms, reactions, nstart, nend));
     }
 
     for msg in array.unwrap().iter().rev() {
+        if msg["type"].as_str().unwrap_or("") == "m.reaction" {
+            let id = msg["event_id"].as_str().unwrap_or("");
+            let sender = msg["sender"].as_str().unwrap_or("");
+            if let Some(r) = parse_reaction(id, sender, &msg["content"]) {
+                reactions.push(r);
+            }
+        }
+
         if msg["type"].as_str().unwrap_or("") != "m.room.message" {
             continue;
         }
@@ -688,16 +1311,73 @@ pub fn get_initial_room_messages(baseu: &Url,
     }
 
     if ms.len() < get {
-        let (more, s, e) =
+        let (more, rs, s, e) =
             get_initial_room_messages(baseu, tk, roomid, get, limit * 2, Some(nend))?;
         nstart = s;
         nend = e;
         for m in more.iter().rev() {
             ms.insert(0, m.clone());
         }
+        reactions.extend(rs);
+    }
+
+    Ok((ms, reactions, nstart, nend))
+}
+
+/// Gets the messages from the @from token backwards, until we find the
+/// @until event or the room start.
+///
+/// It stops after @maxpages requests, so we don't load the whole room
+/// history, in that case the returned token can be used to continue.
+///
+/// The other events in the gap, like reactions and redactions, are returned
+/// too because they can change the messages that we already have.
+pub fn get_gap_messages(baseu: &Url,
+                        tk: String,
+                        roomid: String,
+                        from: String,
+                        until: String,
+                        maxpages: i32)
+                        -> Result<(Vec<Message>, Vec<Event>, Option<String>), Error> {
+    let mut ms: Vec<Message> = vec![];
+    let mut evs: Vec<Event> = vec![];
+    let mut from = from;
+
+    for _ in 0..maxpages {
+        let params = vec![
+            ("dir", strn!("b")),
+            ("limit", strn!("20")),
+            ("from", from.clone()),
+            ("access_token", tk.clone()),
+        ];
+
+        let path = format!("rooms/{}/messages", roomid);
+        let url = client_url!(baseu, &path, params)?;
+
+        let r = json_q("get", &url, &json!(null), timeout!())?;
+        let array = r["chunk"].as_array();
+        if array.is_none() || array.unwrap().len() == 0 {
+            return Ok((ms, evs, None));
+        }
+
+        for ev in array.unwrap().iter() {
+            if ev["event_id"].as_str().unwrap_or("") == until {
+                return Ok((ms, evs, None));
+            }
+
+            if ev["type"].as_str().unwrap_or("") != "m.room.message" {
+                evs.insert(0, parse_event(&roomid, ev));
+                continue;
+            }
+
+            let m = parse_room_message(&baseu, roomid.clone(), ev);
+            ms.insert(0, m);
+        }
+
+        from = String::from(r["end"].as_str().unwrap_or(""));
     }
 
-    Ok((ms, nstart, nend))
+    Ok((ms, evs, Some(from)))
 }
 
 pub fn build_url(base: &Url, path: &str, params: Vec<(&str, String)>) -> Result<Url, Error> {
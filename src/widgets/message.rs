@@ -8,33 +8,65 @@ use self::gtk::prelude::*;
 
 use types::Message;
 use types::Member;
+use types::Receipt;
+use types::Reaction;
 
 use self::chrono::prelude::*;
 
 use backend::BKCommand;
 
 use util;
+use html;
 
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc::TryRecvError;
 use std::path::Path;
 
 use app::AppOp;
+use widgets;
 
 // Room Message item
 pub struct MessageBox<'a> {
     msg: &'a Message,
     op: &'a AppOp,
     username: gtk::Label,
+    receipts: gtk::Box,
+    presence: gtk::Label,
+    reactions: gtk::Box,
 }
 
+// the reactions of the quick reaction menu
+const QUICK_REACTIONS: [&'static str; 8] = ["👍", "👎", "😄", "🎉", "😕", "❤️", "🚀", "👀"];
+
 impl<'a> MessageBox<'a> {
     pub fn new(msg: &'a Message, op: &'a AppOp) -> MessageBox<'a> {
         let username = gtk::Label::new("");
-        MessageBox { msg: msg, op: op, username }
+        let receipts = gtk::Box::new(gtk::Orientation::Horizontal, 2);
+        let presence = gtk::Label::new("");
+        let reactions = gtk::Box::new(gtk::Orientation::Horizontal, 4);
+        MessageBox { msg: msg, op: op, username, receipts, presence, reactions }
+    }
+
+    /// The box with the reaction pills, to update it when the reactions
+    /// to this message change
+    pub fn reactions_box(&self) -> gtk::Box {
+        self.reactions.clone()
+    }
+
+    /// The presence dot over the sender avatar, to update it when the
+    /// sender presence changes
+    pub fn presence_label(&self) -> gtk::Label {
+        self.presence.clone()
+    }
+
+    /// The box with the avatars of the members that have read until this
+    /// message, to update it when new receipts arrive
+    pub fn receipts_box(&self) -> gtk::Box {
+        self.receipts.clone()
     }
 
-    pub fn widget(&self) -> gtk::Box {
+    pub fn widget(&self) -> gtk::EventBox {
         // msg
         // +--------+---------+
         // | avatar | content |
@@ -44,15 +76,26 @@ impl<'a> MessageBox<'a> {
         let content = self.build_room_msg_content(false);
         let avatar = self.build_room_msg_avatar();
 
-        msg_widget.pack_start(&avatar, false, false, 5);
+        // the presence dot goes in the bottom right corner of the avatar
+        let overlay = gtk::Overlay::new();
+        overlay.set_valign(gtk::Align::Start);
+        overlay.add(&avatar);
+        let presence = self.op.get_presence(&self.msg.sender);
+        self.presence.set_markup(&util::presence_markup(&presence));
+        self.presence.set_halign(gtk::Align::End);
+        self.presence.set_valign(gtk::Align::End);
+        overlay.add_overlay(&self.presence);
+
+        msg_widget.pack_start(&overlay, false, false, 5);
         msg_widget.pack_start(&content, true, true, 0);
 
-        msg_widget.show_all();
+        let eb = self.connect_msg_menu(&msg_widget);
+        eb.show_all();
 
-        msg_widget
+        eb
     }
 
-    pub fn small_widget(&self) -> gtk::Box {
+    pub fn small_widget(&self) -> gtk::EventBox {
         // msg
         // +--------+---------+
         // |        | content |
@@ -62,18 +105,115 @@ impl<'a> MessageBox<'a> {
         let content = self.build_room_msg_content(true);
         msg_widget.pack_start(&content, true, true, 55);
 
-        msg_widget.show_all();
+        let eb = self.connect_msg_menu(&msg_widget);
+        eb.show_all();
+
+        eb
+    }
+
+    /// Wraps the message widget in an EventBox that shows the message
+    /// actions menu on right click
+    fn connect_msg_menu(&self, msg_widget: &gtk::Box) -> gtk::EventBox {
+        let eb = gtk::EventBox::new();
+        eb.add(msg_widget);
+
+        if let Some(menu) = self.build_msg_menu() {
+            eb.connect_button_press_event(move |_, ev| {
+                if ev.get_button() == 3 {
+                    menu.popup_easy(3, ev.get_time());
+                    Inhibit(true)
+                } else {
+                    Inhibit(false)
+                }
+            });
+        }
+
+        eb
+    }
+
+    fn build_msg_menu(&self) -> Option<gtk::Menu> {
+        let msg = self.msg;
+        // messages that aren't sent yet or that are removed have no actions
+        if msg.id.is_empty() || msg.redacted {
+            return None;
+        }
+
+        let menu = gtk::Menu::new();
+        let window: gtk::Window = self.op.gtk_builder
+            .get_object("main_window")
+            .expect("Couldn't find main_window in ui file.");
+
+        let item = gtk::MenuItem::new_with_label("Reply");
+        let backend = self.op.backend.clone();
+        let m = msg.clone();
+        item.connect_activate(move |_| {
+            backend.send(BKCommand::ReplyClicked(m.clone())).unwrap();
+        });
+        menu.append(&item);
+
+        let item = gtk::MenuItem::new_with_label("React");
+        let reactions = gtk::Menu::new();
+        for key in QUICK_REACTIONS.iter() {
+            let key = *key;
+            let r = gtk::MenuItem::new_with_label(key);
+            let backend = self.op.backend.clone();
+            let room = msg.room.clone();
+            let id = msg.id.clone();
+            r.connect_activate(move |_| {
+                backend.send(BKCommand::SendReaction(room.clone(), id.clone(), strn!(key))).unwrap();
+            });
+            reactions.append(&r);
+        }
+        item.set_submenu(Some(&reactions));
+        menu.append(&item);
+
+        if msg.sender == self.op.uid && msg.mtype == "m.text" {
+            let item = gtk::MenuItem::new_with_label("Edit");
+            let backend = self.op.backend.clone();
+            let m = msg.clone();
+            item.connect_activate(move |_| {
+                backend.send(BKCommand::EditClicked(m.clone())).unwrap();
+            });
+            menu.append(&item);
+        }
+
+        if self.op.can_redact(msg) {
+            let item = gtk::MenuItem::new_with_label("Remove");
+            let backend = self.op.backend.clone();
+            let room = msg.room.clone();
+            let id = msg.id.clone();
+            item.connect_activate(move |_| {
+                let backend = backend.clone();
+                let room = room.clone();
+                let id = id.clone();
+                widgets::reason_dialog(&window, "Remove message", "Remove", move |reason| {
+                    backend.send(BKCommand::Redact(room.clone(), id.clone(), reason)).unwrap();
+                });
+            });
+            menu.append(&item);
+        }
+
+        if menu.get_children().is_empty() {
+            return None;
+        }
 
-        msg_widget
+        menu.show_all();
+        Some(menu)
     }
 
     fn build_room_msg_content(&self, small: bool) -> gtk::Box {
         // content
-        // +------+
-        // | info |
-        // +------+
-        // | body |
-        // +------+
+        // +-----------+
+        // | info      |
+        // +-----------+
+        // | quote     |
+        // +-----------+
+        // | body      |
+        // +-----------+
+        // | reactions |
+        // +-----------+
+        // | receipts  |
+        // +-----------+
         let content = gtk::Box::new(gtk::Orientation::Vertical, 0);
         let msg = self.msg;
 
@@ -82,9 +222,19 @@ impl<'a> MessageBox<'a> {
             content.pack_start(&info, false, false, 0);
         }
 
+        if let Some(ref evid) = msg.in_reply_to {
+            if !msg.redacted {
+                let quote = self.build_room_msg_quote(evid);
+                content.pack_start(&quote, false, false, 0);
+            }
+        }
+
         let body: gtk::Box;
 
         match msg.mtype.as_ref() {
+            _ if msg.redacted => {
+                body = self.build_room_msg_redacted();
+            }
             "m.image" => {
                 body = self.build_room_msg_image();
             }
@@ -98,9 +248,148 @@ impl<'a> MessageBox<'a> {
 
         content.pack_start(&body, true, true, 0);
 
+        self.reactions.set_halign(gtk::Align::Start);
+        self.fill_reactions(&self.reactions);
+        content.pack_start(&self.reactions, false, false, 0);
+
+        self.receipts.set_halign(gtk::Align::End);
+        self.fill_receipts(&self.receipts);
+        content.pack_start(&self.receipts, false, false, 0);
+
         content
     }
 
+    /// Shows a pill for each reaction key with the number of members that
+    /// have reacted with it. Clicking the pill adds our reaction, or removes
+    /// it if we've already reacted with that key.
+    pub fn fill_reactions(&self, bx: &gtk::Box) {
+        for ch in bx.get_children().iter() {
+            bx.remove(ch);
+        }
+
+        let msg = self.msg;
+        let empty = vec![];
+        let reactions = match self.op.rooms.get(&msg.room) {
+            Some(r) => r.reactions.get(&msg.id).unwrap_or(&empty),
+            None => &empty,
+        };
+
+        // grouped by key, in the order of the first reaction with each key
+        let mut keys: Vec<(String, Vec<&Reaction>)> = vec![];
+        for r in reactions.iter() {
+            if let Some(k) = keys.iter_mut().find(|k| k.0 == r.key) {
+                k.1.push(r);
+                continue;
+            }
+            keys.push((r.key.clone(), vec![r]));
+        }
+
+        for (key, rs) in keys {
+            let btn = gtk::Button::new_with_label(&format!("{} {}", key, rs.len()));
+
+            let names: Vec<String> = rs.iter().map(|r| match self.op.members.get(&r.sender) {
+                Some(m) => m.get_alias(),
+                None => r.sender.clone(),
+            }).collect();
+            btn.set_tooltip_text(&names.join(", ")[..]);
+
+            let mine = rs.iter().find(|r| r.sender == self.op.uid).map(|r| r.id.clone());
+            if mine.is_some() {
+                btn.get_style_context().unwrap().add_class("suggested-action");
+            }
+
+            let backend = self.op.backend.clone();
+            let room = msg.room.clone();
+            let id = msg.id.clone();
+            btn.connect_clicked(move |_| {
+                let cmd = match mine {
+                    Some(ref r) => BKCommand::Redact(room.clone(), r.clone(), String::new()),
+                    None => BKCommand::SendReaction(room.clone(), id.clone(), key.clone()),
+                };
+                backend.send(cmd).unwrap();
+            });
+
+            bx.pack_start(&btn, false, false, 0);
+        }
+
+        bx.show_all();
+    }
+
+    /// Shows the members whose last read message is this one, excluding
+    /// ourselves
+    pub fn fill_receipts(&self, bx: &gtk::Box) {
+        for ch in bx.get_children().iter() {
+            bx.remove(ch);
+        }
+
+        let msg = self.msg;
+        if msg.id.is_empty() {
+            return;
+        }
+
+        let mut receipts: Vec<&Receipt> = match self.op.rooms.get(&msg.room) {
+            Some(r) => r.receipts.values()
+                .filter(|r| r.event == msg.id && r.uid != self.op.uid)
+                .collect(),
+            None => vec![],
+        };
+        receipts.sort_by_key(|r| r.date);
+
+        let mut tooltip = vec![];
+        for r in receipts.iter() {
+            let name = match self.op.members.get(&r.uid) {
+                Some(m) => m.get_alias(),
+                None => r.uid.clone(),
+            };
+            let date = r.date.format("%d/%b/%y %H:%M").to_string();
+            tooltip.push(format!("{} {}", name, date));
+
+            bx.pack_start(&self.build_receipt_avatar(&r.uid), false, false, 0);
+        }
+
+        // the receipts can move to a newer message, so the box can be empty
+        if tooltip.is_empty() {
+            bx.set_tooltip_text(None);
+        } else {
+            bx.set_tooltip_text(&format!("Seen by:\n{}", tooltip.join("\n"))[..]);
+        }
+        bx.show_all();
+    }
+
+    fn build_receipt_avatar(&self, uid: &str) -> gtk::Image {
+        let backend = self.op.backend.clone();
+        let avatar;
+
+        let fname = util::cache_path(uid).unwrap_or(strn!(""));
+        let p = Path::new(&fname);
+        if p.is_file() {
+            avatar = gtk::Image::new();
+            if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&fname, 16, 16, false) {
+                avatar.set_from_pixbuf(&pixbuf);
+            }
+        } else {
+            avatar = gtk::Image::new_from_icon_name("avatar-default-symbolic", 1);
+
+            // the avatar is downloaded to the cache, so we only ask for it
+            // until we've got it once
+            let a = avatar.clone();
+            let (tx, rx): (Sender<(String, String)>, Receiver<(String, String)>) = channel();
+            backend.send(BKCommand::GetUserInfoAsync(strn!(uid), tx)).unwrap();
+            gtk::timeout_add(50, move || match rx.try_recv() {
+                Err(TryRecvError::Empty) => gtk::Continue(true),
+                Err(TryRecvError::Disconnected) => gtk::Continue(false),
+                Ok((_, avatar)) => {
+                    if let Ok(pixbuf) = Pixbuf::new_from_file_at_scale(&avatar, 16, 16, false) {
+                        a.set_from_pixbuf(&pixbuf);
+                    }
+                    gtk::Continue(false)
+                }
+            });
+        }
+
+        avatar
+    }
+
     fn build_room_msg_avatar(&self) -> gtk::Image {
         let sender = self.msg.sender.clone();
         let backend = self.op.backend.clone();
@@ -158,12 +447,27 @@ impl<'a> MessageBox<'a> {
 
         let uname = &self.op.username;
 
+        // the html formatted body, if we can render it, or the plain body
+        let mut markup = match self.msg.formatted {
+            Some(ref f) => html::to_pango(f).unwrap_or(util::markup(body)),
+            None => util::markup(body),
+        };
+
+        // emotes are shown as "* name does something"
+        if self.msg.mtype == "m.emote" {
+            let name = match self.op.members.get(&self.msg.sender) {
+                Some(m) => m.get_alias(),
+                None => self.msg.sender.clone(),
+            };
+            markup = format!("* <b>{}</b> {}", util::html_escape(&name), markup);
+        }
+
         if self.msg.id.is_empty() {
-            msg.set_markup(&format!("<span color=\"#aaaaaa\">{}</span>", util::markup(body)));
+            msg.set_markup(&format!("<span color=\"#aaaaaa\">{}</span>", markup));
         } else if String::from(body).contains(uname) {
-            msg.set_markup(&format!("<span color=\"#ff888e\">{}</span>", util::markup(body)));
+            msg.set_markup(&format!("<span color=\"#ff888e\">{}</span>", markup));
         } else {
-            msg.set_markup(&util::markup(body));
+            msg.set_markup(&markup);
         }
 
         msg.set_line_wrap(true);
@@ -173,6 +477,126 @@ impl<'a> MessageBox<'a> {
         msg.set_alignment(0 as f32, 0 as f32);
         msg.set_selectable(true);
 
+        // notices are sent by bots, so they're less important
+        if self.msg.mtype == "m.notice" {
+            msg.get_style_context().unwrap().add_class("dim-label");
+        }
+
+        bx.add(&msg);
+
+        if self.msg.edited {
+            let edited = gtk::Label::new("");
+            edited.set_markup("<span alpha=\"60%\"><small>(edited)</small></span>");
+            edited.set_valign(gtk::Align::End);
+            bx.pack_start(&edited, false, false, 6);
+        }
+
+        bx
+    }
+
+    /// The quoted message that this one replies to, if it's not in the room
+    /// messages we ask for it to the server. Clicking the quote goes to the
+    /// original message.
+    fn build_room_msg_quote(&self, evid: &str) -> gtk::Button {
+        // quote
+        // +---+--------+
+        // |   | sender |
+        // | | +--------+
+        // |   | body   |
+        // +---+--------+
+        let btn = gtk::Button::new();
+        btn.set_relief(gtk::ReliefStyle::None);
+        btn.set_halign(gtk::Align::Start);
+
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        let sep = gtk::Separator::new(gtk::Orientation::Vertical);
+        let quote = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        let sender = gtk::Label::new("");
+        let body = gtk::Label::new("");
+        sender.set_halign(gtk::Align::Start);
+        body.set_halign(gtk::Align::Start);
+        body.set_ellipsize(pango::EllipsizeMode::End);
+        body.set_max_width_chars(80);
+
+        quote.pack_start(&sender, false, false, 0);
+        quote.pack_start(&body, false, false, 0);
+        bx.pack_start(&sep, false, false, 0);
+        bx.pack_start(&quote, true, true, 0);
+        btn.add(&bx);
+
+        let room = self.msg.room.clone();
+        let parent = match self.op.rooms.get(&room) {
+            Some(r) => r.messages.iter().find(|m| m.id == evid).cloned(),
+            None => None,
+        };
+
+        match parent {
+            Some(p) => {
+                let name = match self.op.members.get(&p.sender) {
+                    Some(m) => m.get_alias(),
+                    None => p.sender.clone(),
+                };
+                fill_quote(&sender, &body, &name, &p);
+            }
+            None => {
+                body.set_markup("<span alpha=\"60%\">Loading…</span>");
+
+                // the fetched messages are cached, so we only ask for
+                // them once and not in each redraw
+                let key = (room.clone(), strn!(evid));
+                let requested = self.op.quotes.lock().unwrap().contains_key(&key);
+                if !requested {
+                    self.op.quotes.lock().unwrap().insert(key.clone(), None);
+
+                    let quotes = self.op.quotes.clone();
+                    let k = key.clone();
+                    let (tx, rx): (Sender<Message>, Receiver<Message>) = channel();
+                    self.op.backend.send(BKCommand::GetMessageAsync(room.clone(), strn!(evid), tx)).unwrap();
+                    gtk::timeout_add(50, move || match rx.try_recv() {
+                        Err(TryRecvError::Empty) => gtk::Continue(true),
+                        Err(TryRecvError::Disconnected) => {
+                            quotes.lock().unwrap().remove(&k);
+                            gtk::Continue(false)
+                        }
+                        Ok(p) => {
+                            quotes.lock().unwrap().insert(k.clone(), Some(p));
+                            gtk::Continue(false)
+                        }
+                    });
+                }
+
+                let s = sender.clone();
+                let b = body.clone();
+                let quotes = self.op.quotes.clone();
+                gtk::timeout_add(50, move || match quotes.lock().unwrap().get(&key) {
+                    Some(&None) => gtk::Continue(true),
+                    Some(&Some(ref p)) => {
+                        fill_quote(&s, &b, &p.sender, p);
+                        gtk::Continue(false)
+                    }
+                    None => {
+                        b.set_markup("<span alpha=\"60%\">Message not found</span>");
+                        gtk::Continue(false)
+                    }
+                });
+            }
+        }
+
+        let backend = self.op.backend.clone();
+        let id = strn!(evid);
+        btn.connect_clicked(move |_| {
+            backend.send(BKCommand::QuoteClicked(room.clone(), id.clone())).unwrap();
+        });
+
+        btn
+    }
+
+    fn build_room_msg_redacted(&self) -> gtk::Box {
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let msg = gtk::Label::new("");
+        msg.set_markup("<span color=\"#aaaaaa\"><i>message deleted</i></span>");
+        msg.set_halign(gtk::Align::Start);
+
         bx.add(&msg);
         bx
     }
@@ -252,3 +676,14 @@ impl<'a> MessageBox<'a> {
         info
     }
 }
+
+fn fill_quote(sender: &gtk::Label, body: &gtk::Label, name: &str, msg: &Message) {
+    sender.set_markup(&format!("<small><b>{}</b></small>", util::html_escape(name)));
+
+    let text = if msg.redacted {
+        strn!("message deleted")
+    } else {
+        msg.body.lines().next().unwrap_or("").to_string()
+    };
+    body.set_markup(&format!("<span alpha=\"60%\">{}</span>", util::html_escape(&text)));
+}
@@ -1,5 +1,7 @@
 mod message;
 mod room;
+mod reason;
 
 pub use self::message::MessageBox;
 pub use self::room::RoomBox;
+pub use self::reason::reason_dialog;
@@ -0,0 +1,59 @@
+extern crate gtk;
+
+use self::gtk::prelude::*;
+
+/// Asks for an optional reason before doing an action over other event or
+/// user, like removing a message. The callback is called with the reason,
+/// that can be empty, only if the user confirms the action.
+pub fn reason_dialog<F>(window: &gtk::Window, title: &str, action: &str, cb: F)
+    where F: Fn(String) + 'static {
+    let dialog = gtk::Dialog::new_with_buttons(
+        Some(title),
+        Some(window),
+        gtk::DIALOG_MODAL|
+        gtk::DIALOG_USE_HEADER_BAR|
+        gtk::DIALOG_DESTROY_WITH_PARENT,
+        &[]);
+
+    let entry = gtk::Entry::new();
+    entry.set_placeholder_text("Reason (optional)");
+
+    let content = dialog.get_content_area();
+    content.set_border_width(12);
+    content.add(&entry);
+    content.show_all();
+
+    if let Some(hbar) = dialog.get_header_bar() {
+        let bar = hbar.downcast::<gtk::HeaderBar>().unwrap();
+        let closebtn = gtk::Button::new_with_label("Cancel");
+        let okbtn = gtk::Button::new_with_label(action);
+        okbtn.get_style_context().unwrap().add_class("destructive-action");
+
+        bar.set_show_close_button(false);
+        bar.pack_start(&closebtn);
+        bar.pack_end(&okbtn);
+        bar.show_all();
+
+        let d = dialog.clone();
+        closebtn.connect_clicked(move |_| {
+            d.destroy();
+        });
+
+        let d = dialog.clone();
+        let e = entry.clone();
+        okbtn.connect_clicked(move |_| {
+            cb(e.get_text().unwrap_or(String::new()));
+            d.destroy();
+        });
+
+        // the buttons aren't dialog responses, so there's no default
+        // widget and we confirm on enter here
+        let ok = okbtn.clone();
+        entry.connect_activate(move |_| {
+            ok.clicked();
+        });
+    }
+
+    dialog.present();
+    entry.grab_focus();
+}